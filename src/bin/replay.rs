@@ -0,0 +1,46 @@
+use market_fsk::{replay_file, MarketConfig};
+use std::env;
+use std::process::ExitCode;
+
+//usage: replay <log file> [config file]
+//without a config file the market checks its invariants after every replayed operation
+//exits with 0 if the market agrees with the whole log, 1 at the first divergence, 2 on errors
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.len() > 2 {
+        eprintln!("usage: replay <log file> [config file]");
+        return ExitCode::from(2);
+    }
+    let config = match args.get(1) {
+        None => MarketConfig::builder()
+            .check_invariants(true)
+            .build()
+            .unwrap(),
+        Some(path) => match MarketConfig::from_file(path) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("{}", err);
+                return ExitCode::from(2);
+            }
+        },
+    };
+    match replay_file(&args[0], config) {
+        Ok(report) => match report.divergence {
+            None => {
+                println!("replayed {} entries, no divergence", report.replayed);
+                ExitCode::SUCCESS
+            }
+            Some(divergence) => {
+                println!(
+                    "replayed {} entries, then diverged at {}",
+                    report.replayed, divergence
+                );
+                ExitCode::from(1)
+            }
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(2)
+        }
+    }
+}
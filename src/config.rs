@@ -0,0 +1,1107 @@
+use crate::log::{LogFormat, LogSinkMode};
+use crate::pricing::PricingMode;
+use crate::TokenMode;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_LOCK_INITIAL_TTL: u64 = 9;
+//higher -> greedier
+pub const DEFAULT_MARKET_GREEDINESS: f32 = 1.01;
+
+pub const DEFAULT_BLACK_FRIDAY_DISCOUNT: f32 = 0.20; //discount the goods of 20%
+
+pub const DEFAULT_EXCHANGE_RATE_CHANGE_RATE_OVER_TIME: f32 = 0.999;
+
+pub const DEFAULT_TRADER_INACTIVITY_TIME: u64 = 5;
+
+//black friday begins when time % period == start and lasts duration ticks
+pub const DEFAULT_BLACK_FRIDAY_PERIOD: u64 = 7;
+pub const DEFAULT_BLACK_FRIDAY_START: u64 = 4;
+pub const DEFAULT_BLACK_FRIDAY_DURATION: u64 = 1;
+
+pub const DEFAULT_MAX_LOCK_RENEWALS: u32 = 3;
+//a lock lives at most 9 * (1 + 3) ticks, its token is remembered for much longer than that
+pub const DEFAULT_RETIRED_TOKEN_TTL: u64 = 1000;
+pub const DEFAULT_LOCK_RENEWAL_FEE: f32 = 0.; //fraction of the lock price charged at every renewal
+
+pub const DEFAULT_LOG_PATH: &str = "log_FSK.txt";
+pub const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+pub const DEFAULT_MARKET_NAME: &str = "FSK";
+//{market} is replaced by the market name, {tick} by the market time and {timestamp} by the wall-clock time
+pub const DEFAULT_SNAPSHOT_NAME_TEMPLATE: &str = "market_{market}_snapshot_{tick}.json";
+pub const DEFAULT_DROP_SNAPSHOT_NAME_TEMPLATE: &str = "market_{market}_snapshot_at_drop.json";
+
+/// Keys accepted at the top level of a config file.
+const CONFIG_FILE_FIELDS: [&str; 35] = [
+    "lock_initial_ttl",
+    "market_greediness",
+    "black_friday_discount",
+    "black_friday_period",
+    "black_friday_start",
+    "black_friday_duration",
+    "exchange_rate_change_rate_over_time",
+    "trader_inactivity_time",
+    "initial_quantities",
+    "log_path",
+    "log_format",
+    "log_sink",
+    "log_max_bytes",
+    "log_rotate_every_ticks",
+    "log_keep_rotated",
+    "snapshot_dir",
+    "pricing_mode",
+    "max_lock_renewals",
+    "lock_renewal_fee",
+    "max_locks_per_trader",
+    "max_locked_quantity_per_good",
+    "max_locked_eur_per_trader",
+    "market_name",
+    "snapshot_name_template",
+    "drop_snapshot_name_template",
+    "snapshot_keep_last",
+    "snapshot_keep_every",
+    "snapshot_on_drop",
+    "snapshot_every_ticks",
+    "snapshot_on_trade",
+    "snapshot_on_price_move",
+    "seed",
+    "token_mode",
+    "retired_token_ttl",
+    "check_invariants",
+];
+
+/// Placeholders accepted in snapshot name templates.
+const SNAPSHOT_NAME_PLACEHOLDERS: [&str; 3] = ["{market}", "{tick}", "{timestamp}"];
+
+/// Keys accepted inside the `initial_quantities` table of a config file.
+const INITIAL_QUANTITIES_FIELDS: [&str; 4] = ["eur", "yen", "usd", "yuan"];
+
+/// Quantities a market starts with, instead of a random allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct InitialQuantities {
+    pub eur: f32,
+    pub yen: f32,
+    pub usd: f32,
+    pub yuan: f32,
+}
+
+/// Behaviour knobs of an `FskMarket`.
+///
+/// A config can only be obtained through `MarketConfig::default()` or through a
+/// `MarketConfigBuilder`, so every config a market receives has been validated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarketConfig {
+    pub(crate) lock_initial_ttl: u64,
+    pub(crate) market_greediness: f32,
+    pub(crate) black_friday_discount: f32,
+    pub(crate) exchange_rate_change_rate_over_time: f32,
+    pub(crate) trader_inactivity_time: u64,
+    pub(crate) black_friday_period: u64,
+    pub(crate) black_friday_start: u64,
+    pub(crate) black_friday_duration: u64,
+    pub(crate) initial_quantities: Option<InitialQuantities>,
+    pub(crate) log_path: PathBuf,
+    pub(crate) log_format: LogFormat,
+    pub(crate) log_sink: LogSinkMode,
+    pub(crate) log_max_bytes: Option<u64>,
+    pub(crate) log_rotate_every_ticks: Option<u64>,
+    pub(crate) log_keep_rotated: Option<usize>,
+    pub(crate) snapshot_dir: PathBuf,
+    pub(crate) pricing_mode: PricingMode,
+    pub(crate) max_lock_renewals: u32,
+    pub(crate) lock_renewal_fee: f32,
+    pub(crate) max_locks_per_trader: Option<u32>,
+    pub(crate) max_locked_quantity_per_good: Option<f32>,
+    pub(crate) max_locked_eur_per_trader: Option<f32>,
+    pub(crate) market_name: String,
+    pub(crate) snapshot_name_template: String,
+    pub(crate) drop_snapshot_name_template: String,
+    pub(crate) snapshot_keep_last: Option<usize>,
+    pub(crate) snapshot_keep_every: Option<u64>,
+    pub(crate) snapshot_on_drop: bool,
+    pub(crate) snapshot_every_ticks: Option<u64>,
+    pub(crate) snapshot_on_trade: bool,
+    pub(crate) snapshot_on_price_move: Option<f32>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) token_mode: TokenMode,
+    pub(crate) retired_token_ttl: u64,
+    pub(crate) check_invariants: bool,
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        MarketConfig {
+            lock_initial_ttl: DEFAULT_LOCK_INITIAL_TTL,
+            market_greediness: DEFAULT_MARKET_GREEDINESS,
+            black_friday_discount: DEFAULT_BLACK_FRIDAY_DISCOUNT,
+            exchange_rate_change_rate_over_time: DEFAULT_EXCHANGE_RATE_CHANGE_RATE_OVER_TIME,
+            trader_inactivity_time: DEFAULT_TRADER_INACTIVITY_TIME,
+            black_friday_period: DEFAULT_BLACK_FRIDAY_PERIOD,
+            black_friday_start: DEFAULT_BLACK_FRIDAY_START,
+            black_friday_duration: DEFAULT_BLACK_FRIDAY_DURATION,
+            initial_quantities: None,
+            log_path: PathBuf::from(DEFAULT_LOG_PATH),
+            log_format: LogFormat::Legacy,
+            log_sink: LogSinkMode::File,
+            log_max_bytes: None,
+            log_rotate_every_ticks: None,
+            log_keep_rotated: None,
+            snapshot_dir: PathBuf::from(DEFAULT_SNAPSHOT_DIR),
+            pricing_mode: PricingMode::Default,
+            max_lock_renewals: DEFAULT_MAX_LOCK_RENEWALS,
+            lock_renewal_fee: DEFAULT_LOCK_RENEWAL_FEE,
+            max_locks_per_trader: None,
+            max_locked_quantity_per_good: None,
+            max_locked_eur_per_trader: None,
+            market_name: DEFAULT_MARKET_NAME.to_string(),
+            snapshot_name_template: DEFAULT_SNAPSHOT_NAME_TEMPLATE.to_string(),
+            drop_snapshot_name_template: DEFAULT_DROP_SNAPSHOT_NAME_TEMPLATE.to_string(),
+            snapshot_keep_last: None,
+            snapshot_keep_every: None,
+            snapshot_on_drop: true,
+            snapshot_every_ticks: None,
+            snapshot_on_trade: false,
+            snapshot_on_price_move: None,
+            seed: None,
+            token_mode: TokenMode::Legacy,
+            retired_token_ttl: DEFAULT_RETIRED_TOKEN_TTL,
+            check_invariants: false,
+        }
+    }
+}
+
+impl MarketConfig {
+    pub fn builder() -> MarketConfigBuilder {
+        MarketConfigBuilder::new()
+    }
+
+    /// Number of ticks a lock stays valid.
+    pub fn lock_initial_ttl(&self) -> u64 {
+        self.lock_initial_ttl
+    }
+
+    /// Ratio between buy and sell exchange rates, higher -> greedier.
+    pub fn market_greediness(&self) -> f32 {
+        self.market_greediness
+    }
+
+    /// Fraction taken off the buy exchange rates during black friday.
+    pub fn black_friday_discount(&self) -> f32 {
+        self.black_friday_discount
+    }
+
+    /// Factor applied to the exchange rates at every tick of trader inactivity.
+    pub fn exchange_rate_change_rate_over_time(&self) -> f32 {
+        self.exchange_rate_change_rate_over_time
+    }
+
+    /// Number of ticks without trader interactions after which exchange rates start decreasing.
+    pub fn trader_inactivity_time(&self) -> u64 {
+        self.trader_inactivity_time
+    }
+
+    /// Black friday repeats every `black_friday_period` ticks.
+    pub fn black_friday_period(&self) -> u64 {
+        self.black_friday_period
+    }
+
+    /// Tick of the period (`time % black_friday_period`) at which black friday begins.
+    pub fn black_friday_start(&self) -> u64 {
+        self.black_friday_start
+    }
+
+    /// Number of ticks black friday lasts.
+    pub fn black_friday_duration(&self) -> u64 {
+        self.black_friday_duration
+    }
+
+    /// Quantities the market starts with, `None` means a random allocation.
+    pub fn initial_quantities(&self) -> Option<InitialQuantities> {
+        self.initial_quantities
+    }
+
+    /// File the market log is appended to.
+    pub fn log_path(&self) -> &Path {
+        &self.log_path
+    }
+
+    /// Format of the log lines, the legacy one unless set otherwise.
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format
+    }
+
+    /// Built-in log sink used by `FskMarket::with_config`, a file at `log_path` unless set otherwise.
+    pub fn log_sink(&self) -> LogSinkMode {
+        self.log_sink
+    }
+
+    /// The log is rotated in between operations, once an entry as long as the last one would make it
+    /// bigger than this many bytes. Entries of a single operation always go to the same file.
+    pub fn log_max_bytes(&self) -> Option<u64> {
+        self.log_max_bytes
+    }
+
+    /// The log is rotated every time the market time reaches a multiple of this value, once the event
+    /// that moved it has been handled.
+    pub fn log_rotate_every_ticks(&self) -> Option<u64> {
+        self.log_rotate_every_ticks
+    }
+
+    /// Number of rotated log files to keep, older ones are deleted. `None` keeps every file.
+    pub fn log_keep_rotated(&self) -> Option<usize> {
+        self.log_keep_rotated
+    }
+
+    /// Directory snapshots are written to.
+    pub fn snapshot_dir(&self) -> &Path {
+        &self.snapshot_dir
+    }
+
+    /// Built-in pricing strategy used by `FskMarket::with_config`.
+    pub fn pricing_mode(&self) -> PricingMode {
+        self.pricing_mode
+    }
+
+    /// Number of times a single lock can be extended.
+    pub fn max_lock_renewals(&self) -> u32 {
+        self.max_lock_renewals
+    }
+
+    /// Fraction of the lock price charged every time a lock is extended.
+    pub fn lock_renewal_fee(&self) -> f32 {
+        self.lock_renewal_fee
+    }
+
+    /// Number of open locks, buy and sell, a single trader can hold. `None` means no limit.
+    pub fn max_locks_per_trader(&self) -> Option<u32> {
+        self.max_locks_per_trader
+    }
+
+    /// Quantity of a single good a trader can hold in open locks. `None` means no limit.
+    pub fn max_locked_quantity_per_good(&self) -> Option<f32> {
+        self.max_locked_quantity_per_good
+    }
+
+    /// EUR a trader can hold in open locks, summing the prices of all of them. `None` means no limit.
+    pub fn max_locked_eur_per_trader(&self) -> Option<f32> {
+        self.max_locked_eur_per_trader
+    }
+
+    /// Name of this market instance, used in snapshot names to tell markets sharing a directory apart.
+    /// It can't contain `/`, `\` or `..`.
+    pub fn market_name(&self) -> &str {
+        &self.market_name
+    }
+
+    /// File name of the snapshots taken without an explicit name.
+    ///
+    /// `{market}`, `{tick}` and `{timestamp}` are replaced by the market name, the market time and
+    /// the wall-clock time.
+    pub fn snapshot_name_template(&self) -> &str {
+        &self.snapshot_name_template
+    }
+
+    /// File name of the snapshot taken when the market is dropped, same placeholders as
+    /// `snapshot_name_template`.
+    pub fn drop_snapshot_name_template(&self) -> &str {
+        &self.drop_snapshot_name_template
+    }
+
+    /// Number of most recent snapshots to keep, older ones are deleted. `None` keeps every snapshot.
+    ///
+    /// Only snapshots named after `snapshot_name_template` by this market are ever deleted.
+    pub fn snapshot_keep_last(&self) -> Option<usize> {
+        self.snapshot_keep_last
+    }
+
+    /// Snapshots taken at a tick multiple of this value are never deleted by `snapshot_keep_last`.
+    pub fn snapshot_keep_every(&self) -> Option<u64> {
+        self.snapshot_keep_every
+    }
+
+    /// Whether a snapshot is taken when the market is dropped.
+    pub fn snapshot_on_drop(&self) -> bool {
+        self.snapshot_on_drop
+    }
+
+    /// A snapshot is taken every time the market time reaches a multiple of this value.
+    pub fn snapshot_every_ticks(&self) -> Option<u64> {
+        self.snapshot_every_ticks
+    }
+
+    /// Whether a snapshot is taken after every settled `buy` and `sell`.
+    pub fn snapshot_on_trade(&self) -> bool {
+        self.snapshot_on_trade
+    }
+
+    /// A snapshot is taken when a buy exchange rate moved by more than this fraction since the last
+    /// snapshot named after `snapshot_name_template`.
+    pub fn snapshot_on_price_move(&self) -> Option<f32> {
+        self.snapshot_on_price_move
+    }
+
+    /// Seed of the generator that draws tokens and the random allocation, `None` seeds it from the
+    /// OS. A seeded market writes its tick instead of the wall-clock time in `LogFormat::JsonLines`
+    /// logs, so two markets with the same seed fed the same calls write the same log. Legacy logs
+    /// keep the wall-clock time the log checker expects.
+    ///
+    /// Snapshots of a seeded market save the generator, a seeded market restored from one carries
+    /// on where it stopped instead of drawing the same tokens again.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// How lock tokens are drawn and who can settle them, `TokenMode::Legacy` unless set otherwise.
+    ///
+    /// Trader-bound tokens are only unguessable if `seed` isn't known to the traders.
+    pub fn token_mode(&self) -> TokenMode {
+        self.token_mode
+    }
+
+    /// Number of ticks an expired, settled or cancelled token is remembered for. Within that window
+    /// `buy` and `sell` tell expired tokens from unknown ones, afterwards every retired token is
+    /// unrecognized. Defaults to [`DEFAULT_RETIRED_TOKEN_TTL`].
+    pub fn retired_token_ttl(&self) -> u64 {
+        self.retired_token_ttl
+    }
+
+    /// Whether the market checks after every lock, settlement and tick that no value was created
+    /// or lost and that no quantity or rate went negative, and panics with a dump of its state if
+    /// it was. Off by default, the tests and the replay binary turn it on.
+    pub fn check_invariants(&self) -> bool {
+        self.check_invariants
+    }
+
+    /// Loads a config from a file. Files ending in `.toml` are parsed as TOML, anything else as JSON.
+    ///
+    /// Keys missing from the file keep their default value.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<MarketConfig, ConfigError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|err| ConfigError::Io {
+            path: path.display().to_string(),
+            message: err.to_string(),
+        })?;
+        if path.extension() == Some(OsStr::new("toml")) {
+            MarketConfig::from_toml_str(&content)
+        } else {
+            MarketConfig::from_json_str(&content)
+        }
+    }
+
+    pub fn from_json_str(content: &str) -> Result<MarketConfig, ConfigError> {
+        let value: Value = serde_json::from_str(content).map_err(|err| ConfigError::Parse {
+            message: err.to_string(),
+        })?;
+        MarketConfig::from_value(value)
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<MarketConfig, ConfigError> {
+        let value: toml::Value = toml::from_str(content).map_err(|err| ConfigError::Parse {
+            message: err.to_string(),
+        })?;
+        //go through serde_json so both formats share the same key checks
+        let value = serde_json::to_value(value).map_err(|err| ConfigError::Parse {
+            message: err.to_string(),
+        })?;
+        MarketConfig::from_value(value)
+    }
+
+    fn from_value(value: Value) -> Result<MarketConfig, ConfigError> {
+        let fields = match value {
+            Value::Object(fields) => fields,
+            _ => {
+                return Err(ConfigError::InvalidType {
+                    field: "(root)".to_string(),
+                    expected: "a table of settings",
+                })
+            }
+        };
+        check_known_fields(&fields, &CONFIG_FILE_FIELDS, "")?;
+
+        let mut builder = MarketConfigBuilder::new();
+        if let Some(ttl) = get_u64(&fields, "lock_initial_ttl", "")? {
+            builder = builder.lock_initial_ttl(ttl);
+        }
+        if let Some(greediness) = get_f32(&fields, "market_greediness", "")? {
+            builder = builder.market_greediness(greediness);
+        }
+        if let Some(discount) = get_f32(&fields, "black_friday_discount", "")? {
+            builder = builder.black_friday_discount(discount);
+        }
+        if let Some(period) = get_u64(&fields, "black_friday_period", "")? {
+            builder = builder.black_friday_period(period);
+        }
+        if let Some(start) = get_u64(&fields, "black_friday_start", "")? {
+            builder = builder.black_friday_start(start);
+        }
+        if let Some(duration) = get_u64(&fields, "black_friday_duration", "")? {
+            builder = builder.black_friday_duration(duration);
+        }
+        if let Some(rate) = get_f32(&fields, "exchange_rate_change_rate_over_time", "")? {
+            builder = builder.exchange_rate_change_rate_over_time(rate);
+        }
+        if let Some(ticks) = get_u64(&fields, "trader_inactivity_time", "")? {
+            builder = builder.trader_inactivity_time(ticks);
+        }
+        if let Some(path) = get_str(&fields, "log_path", "")? {
+            builder = builder.log_path(path);
+        }
+        if let Some(dir) = get_str(&fields, "snapshot_dir", "")? {
+            builder = builder.snapshot_dir(dir);
+        }
+        if let Some(renewals) = get_u64(&fields, "max_lock_renewals", "")? {
+            let renewals = u32::try_from(renewals).map_err(|_| ConfigError::OutOfRange {
+                field: "max_lock_renewals",
+                value: renewals.to_string(),
+                expected: "at most 4294967295",
+            })?;
+            builder = builder.max_lock_renewals(renewals);
+        }
+        if let Some(fee) = get_f32(&fields, "lock_renewal_fee", "")? {
+            builder = builder.lock_renewal_fee(fee);
+        }
+        if let Some(locks) = get_u64(&fields, "max_locks_per_trader", "")? {
+            let locks = u32::try_from(locks).map_err(|_| ConfigError::OutOfRange {
+                field: "max_locks_per_trader",
+                value: locks.to_string(),
+                expected: "at most 4294967295",
+            })?;
+            builder = builder.max_locks_per_trader(locks);
+        }
+        if let Some(quantity) = get_f32(&fields, "max_locked_quantity_per_good", "")? {
+            builder = builder.max_locked_quantity_per_good(quantity);
+        }
+        if let Some(eur) = get_f32(&fields, "max_locked_eur_per_trader", "")? {
+            builder = builder.max_locked_eur_per_trader(eur);
+        }
+        if let Some(name) = get_str(&fields, "market_name", "")? {
+            builder = builder.market_name(name);
+        }
+        if let Some(template) = get_str(&fields, "snapshot_name_template", "")? {
+            builder = builder.snapshot_name_template(template);
+        }
+        if let Some(template) = get_str(&fields, "drop_snapshot_name_template", "")? {
+            builder = builder.drop_snapshot_name_template(template);
+        }
+        if let Some(bytes) = get_u64(&fields, "log_max_bytes", "")? {
+            builder = builder.log_max_bytes(bytes);
+        }
+        if let Some(ticks) = get_u64(&fields, "log_rotate_every_ticks", "")? {
+            builder = builder.log_rotate_every_ticks(ticks);
+        }
+        if let Some(count) = get_u64(&fields, "log_keep_rotated", "")? {
+            let count = usize::try_from(count).map_err(|_| ConfigError::OutOfRange {
+                field: "log_keep_rotated",
+                value: count.to_string(),
+                expected: "a number that fits in memory",
+            })?;
+            builder = builder.log_keep_rotated(count);
+        }
+        if let Some(count) = get_u64(&fields, "snapshot_keep_last", "")? {
+            let count = usize::try_from(count).map_err(|_| ConfigError::OutOfRange {
+                field: "snapshot_keep_last",
+                value: count.to_string(),
+                expected: "a number that fits in memory",
+            })?;
+            builder = builder.snapshot_keep_last(count);
+        }
+        if let Some(ticks) = get_u64(&fields, "snapshot_keep_every", "")? {
+            builder = builder.snapshot_keep_every(ticks);
+        }
+        if let Some(on_drop) = get_bool(&fields, "snapshot_on_drop", "")? {
+            builder = builder.snapshot_on_drop(on_drop);
+        }
+        if let Some(ticks) = get_u64(&fields, "snapshot_every_ticks", "")? {
+            builder = builder.snapshot_every_ticks(ticks);
+        }
+        if let Some(on_trade) = get_bool(&fields, "snapshot_on_trade", "")? {
+            builder = builder.snapshot_on_trade(on_trade);
+        }
+        if let Some(threshold) = get_f32(&fields, "snapshot_on_price_move", "")? {
+            builder = builder.snapshot_on_price_move(threshold);
+        }
+        if let Some(seed) = get_u64(&fields, "seed", "")? {
+            builder = builder.seed(seed);
+        }
+        if let Some(ticks) = get_u64(&fields, "retired_token_ttl", "")? {
+            builder = builder.retired_token_ttl(ticks);
+        }
+        if let Some(check) = get_bool(&fields, "check_invariants", "")? {
+            builder = builder.check_invariants(check);
+        }
+        match get_str(&fields, "log_format", "")? {
+            None => {}
+            Some("legacy") => builder = builder.log_format(LogFormat::Legacy),
+            Some("json_lines") => builder = builder.log_format(LogFormat::JsonLines),
+            Some(other) => {
+                return Err(ConfigError::OutOfRange {
+                    field: "log_format",
+                    value: other.to_string(),
+                    expected: "\"legacy\" or \"json_lines\"",
+                })
+            }
+        }
+        match get_str(&fields, "log_sink", "")? {
+            None => {}
+            Some("file") => builder = builder.log_sink(LogSinkMode::File),
+            Some("stderr") => builder = builder.log_sink(LogSinkMode::Stderr),
+            Some("null") => builder = builder.log_sink(LogSinkMode::Null),
+            Some(other) => {
+                return Err(ConfigError::OutOfRange {
+                    field: "log_sink",
+                    value: other.to_string(),
+                    expected: "\"file\", \"stderr\" or \"null\"",
+                })
+            }
+        }
+        match get_str(&fields, "token_mode", "")? {
+            None => {}
+            Some("legacy") => builder = builder.token_mode(TokenMode::Legacy),
+            Some("trader_bound") => builder = builder.token_mode(TokenMode::TraderBound),
+            Some(other) => {
+                return Err(ConfigError::OutOfRange {
+                    field: "token_mode",
+                    value: other.to_string(),
+                    expected: "\"legacy\" or \"trader_bound\"",
+                })
+            }
+        }
+        match get_str(&fields, "pricing_mode", "")? {
+            None => {}
+            Some("default") => builder = builder.pricing_mode(PricingMode::Default),
+            Some("constant_product") => {
+                builder = builder.pricing_mode(PricingMode::ConstantProduct)
+            }
+            Some(other) => {
+                return Err(ConfigError::OutOfRange {
+                    field: "pricing_mode",
+                    value: other.to_string(),
+                    expected: "\"default\" or \"constant_product\"",
+                })
+            }
+        }
+        match fields.get("initial_quantities") {
+            None => {}
+            Some(Value::Object(quantities)) => {
+                let prefix = "initial_quantities.";
+                check_known_fields(quantities, &INITIAL_QUANTITIES_FIELDS, prefix)?;
+                builder = builder.initial_quantities(InitialQuantities {
+                    eur: get_f32(quantities, "eur", prefix)?.unwrap_or(0.),
+                    yen: get_f32(quantities, "yen", prefix)?.unwrap_or(0.),
+                    usd: get_f32(quantities, "usd", prefix)?.unwrap_or(0.),
+                    yuan: get_f32(quantities, "yuan", prefix)?.unwrap_or(0.),
+                });
+            }
+            Some(_) => {
+                return Err(ConfigError::InvalidType {
+                    field: "initial_quantities".to_string(),
+                    expected: "a table with eur, yen, usd and yuan",
+                })
+            }
+        }
+        builder.build()
+    }
+
+    /// Checks that every field is in its allowed range.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.lock_initial_ttl == 0 {
+            return Err(ConfigError::OutOfRange {
+                field: "lock_initial_ttl",
+                value: self.lock_initial_ttl.to_string(),
+                expected: "at least 1",
+            });
+        }
+        if !self.market_greediness.is_finite() || self.market_greediness < 1. {
+            return Err(ConfigError::OutOfRange {
+                field: "market_greediness",
+                value: self.market_greediness.to_string(),
+                expected: "a finite number >= 1",
+            });
+        }
+        if !(0. ..1.).contains(&self.black_friday_discount) {
+            return Err(ConfigError::OutOfRange {
+                field: "black_friday_discount",
+                value: self.black_friday_discount.to_string(),
+                expected: "a number in [0, 1)",
+            });
+        }
+        if !(self.exchange_rate_change_rate_over_time > 0.
+            && self.exchange_rate_change_rate_over_time <= 1.)
+        {
+            return Err(ConfigError::OutOfRange {
+                field: "exchange_rate_change_rate_over_time",
+                value: self.exchange_rate_change_rate_over_time.to_string(),
+                expected: "a number in (0, 1]",
+            });
+        }
+        if self.black_friday_period < 2 {
+            return Err(ConfigError::OutOfRange {
+                field: "black_friday_period",
+                value: self.black_friday_period.to_string(),
+                expected: "at least 2",
+            });
+        }
+        if self.black_friday_start >= self.black_friday_period {
+            return Err(ConfigError::OutOfRange {
+                field: "black_friday_start",
+                value: self.black_friday_start.to_string(),
+                expected: "lower than black_friday_period",
+            });
+        }
+        if self.black_friday_duration == 0 || self.black_friday_duration >= self.black_friday_period
+        {
+            return Err(ConfigError::OutOfRange {
+                field: "black_friday_duration",
+                value: self.black_friday_duration.to_string(),
+                expected: "at least 1 and lower than black_friday_period",
+            });
+        }
+        if !(0. ..=1.).contains(&self.lock_renewal_fee) {
+            return Err(ConfigError::OutOfRange {
+                field: "lock_renewal_fee",
+                value: self.lock_renewal_fee.to_string(),
+                expected: "a number in [0, 1]",
+            });
+        }
+        //the name ends up in snapshot file names, it must not lead out of snapshot_dir
+        let leaves_directory =
+            self.market_name.contains(['/', '\\']) || self.market_name.contains("..");
+        if self.market_name.is_empty() || leaves_directory {
+            return Err(ConfigError::OutOfRange {
+                field: "market_name",
+                value: self.market_name.clone(),
+                expected: "a non-empty name without path separators or ..",
+            });
+        }
+        let templates = [
+            ("snapshot_name_template", &self.snapshot_name_template),
+            (
+                "drop_snapshot_name_template",
+                &self.drop_snapshot_name_template,
+            ),
+        ];
+        for (field, template) in templates {
+            if !is_valid_snapshot_name_template(template) {
+                return Err(ConfigError::OutOfRange {
+                    field,
+                    value: template.clone(),
+                    expected: "a file name using only {market}, {tick} and {timestamp}",
+                });
+            }
+        }
+        if self.log_max_bytes == Some(0) {
+            return Err(ConfigError::OutOfRange {
+                field: "log_max_bytes",
+                value: "0".to_string(),
+                expected: "at least 1",
+            });
+        }
+        if self.log_rotate_every_ticks == Some(0) {
+            return Err(ConfigError::OutOfRange {
+                field: "log_rotate_every_ticks",
+                value: "0".to_string(),
+                expected: "at least 1",
+            });
+        }
+        if self.retired_token_ttl == 0 {
+            return Err(ConfigError::OutOfRange {
+                field: "retired_token_ttl",
+                value: "0".to_string(),
+                expected: "at least 1",
+            });
+        }
+        if self.snapshot_keep_last == Some(0) {
+            return Err(ConfigError::OutOfRange {
+                field: "snapshot_keep_last",
+                value: "0".to_string(),
+                expected: "at least 1",
+            });
+        }
+        if self.snapshot_keep_every == Some(0) {
+            return Err(ConfigError::OutOfRange {
+                field: "snapshot_keep_every",
+                value: "0".to_string(),
+                expected: "at least 1",
+            });
+        }
+        if self.snapshot_every_ticks == Some(0) {
+            return Err(ConfigError::OutOfRange {
+                field: "snapshot_every_ticks",
+                value: "0".to_string(),
+                expected: "at least 1",
+            });
+        }
+        if let Some(threshold) = self.snapshot_on_price_move {
+            if !threshold.is_finite() || threshold <= 0. {
+                return Err(ConfigError::OutOfRange {
+                    field: "snapshot_on_price_move",
+                    value: threshold.to_string(),
+                    expected: "a finite number > 0",
+                });
+            }
+        }
+        let lock_caps = [
+            (
+                "max_locked_quantity_per_good",
+                self.max_locked_quantity_per_good,
+            ),
+            ("max_locked_eur_per_trader", self.max_locked_eur_per_trader),
+        ];
+        for (field, cap) in lock_caps {
+            if let Some(cap) = cap {
+                if !cap.is_finite() || cap < 0. {
+                    return Err(ConfigError::OutOfRange {
+                        field,
+                        value: cap.to_string(),
+                        expected: "a finite number >= 0",
+                    });
+                }
+            }
+        }
+        if let Some(quantities) = self.initial_quantities {
+            let fields = [
+                ("initial_quantities.eur", quantities.eur),
+                ("initial_quantities.yen", quantities.yen),
+                ("initial_quantities.usd", quantities.usd),
+                ("initial_quantities.yuan", quantities.yuan),
+            ];
+            for (field, quantity) in fields {
+                if !quantity.is_finite() || quantity < 0. {
+                    return Err(ConfigError::OutOfRange {
+                        field,
+                        value: quantity.to_string(),
+                        expected: "a finite number >= 0",
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a `MarketConfig`, starting from the default values.
+#[derive(Debug, Clone, Default)]
+pub struct MarketConfigBuilder {
+    config: MarketConfig,
+}
+
+impl MarketConfigBuilder {
+    pub fn new() -> MarketConfigBuilder {
+        MarketConfigBuilder {
+            config: MarketConfig::default(),
+        }
+    }
+
+    pub fn lock_initial_ttl(mut self, ticks: u64) -> Self {
+        self.config.lock_initial_ttl = ticks;
+        self
+    }
+
+    pub fn market_greediness(mut self, greediness: f32) -> Self {
+        self.config.market_greediness = greediness;
+        self
+    }
+
+    pub fn black_friday_discount(mut self, discount: f32) -> Self {
+        self.config.black_friday_discount = discount;
+        self
+    }
+
+    pub fn exchange_rate_change_rate_over_time(mut self, rate: f32) -> Self {
+        self.config.exchange_rate_change_rate_over_time = rate;
+        self
+    }
+
+    pub fn trader_inactivity_time(mut self, ticks: u64) -> Self {
+        self.config.trader_inactivity_time = ticks;
+        self
+    }
+
+    pub fn black_friday_period(mut self, ticks: u64) -> Self {
+        self.config.black_friday_period = ticks;
+        self
+    }
+
+    pub fn black_friday_start(mut self, tick: u64) -> Self {
+        self.config.black_friday_start = tick;
+        self
+    }
+
+    pub fn black_friday_duration(mut self, ticks: u64) -> Self {
+        self.config.black_friday_duration = ticks;
+        self
+    }
+
+    pub fn initial_quantities(mut self, quantities: InitialQuantities) -> Self {
+        self.config.initial_quantities = Some(quantities);
+        self
+    }
+
+    pub fn log_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.log_path = path.into();
+        self
+    }
+
+    pub fn log_format(mut self, format: LogFormat) -> Self {
+        self.config.log_format = format;
+        self
+    }
+
+    pub fn log_sink(mut self, mode: LogSinkMode) -> Self {
+        self.config.log_sink = mode;
+        self
+    }
+
+    pub fn log_max_bytes(mut self, bytes: u64) -> Self {
+        self.config.log_max_bytes = Some(bytes);
+        self
+    }
+
+    pub fn log_rotate_every_ticks(mut self, ticks: u64) -> Self {
+        self.config.log_rotate_every_ticks = Some(ticks);
+        self
+    }
+
+    pub fn log_keep_rotated(mut self, count: usize) -> Self {
+        self.config.log_keep_rotated = Some(count);
+        self
+    }
+
+    pub fn snapshot_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.config.snapshot_dir = dir.into();
+        self
+    }
+
+    pub fn pricing_mode(mut self, mode: PricingMode) -> Self {
+        self.config.pricing_mode = mode;
+        self
+    }
+
+    pub fn max_lock_renewals(mut self, renewals: u32) -> Self {
+        self.config.max_lock_renewals = renewals;
+        self
+    }
+
+    pub fn lock_renewal_fee(mut self, fee: f32) -> Self {
+        self.config.lock_renewal_fee = fee;
+        self
+    }
+
+    pub fn max_locks_per_trader(mut self, locks: u32) -> Self {
+        self.config.max_locks_per_trader = Some(locks);
+        self
+    }
+
+    pub fn max_locked_quantity_per_good(mut self, quantity: f32) -> Self {
+        self.config.max_locked_quantity_per_good = Some(quantity);
+        self
+    }
+
+    pub fn max_locked_eur_per_trader(mut self, eur: f32) -> Self {
+        self.config.max_locked_eur_per_trader = Some(eur);
+        self
+    }
+
+    pub fn market_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.config.market_name = name.into();
+        self
+    }
+
+    pub fn snapshot_name_template<S: Into<String>>(mut self, template: S) -> Self {
+        self.config.snapshot_name_template = template.into();
+        self
+    }
+
+    pub fn drop_snapshot_name_template<S: Into<String>>(mut self, template: S) -> Self {
+        self.config.drop_snapshot_name_template = template.into();
+        self
+    }
+
+    pub fn snapshot_keep_last(mut self, count: usize) -> Self {
+        self.config.snapshot_keep_last = Some(count);
+        self
+    }
+
+    pub fn snapshot_keep_every(mut self, ticks: u64) -> Self {
+        self.config.snapshot_keep_every = Some(ticks);
+        self
+    }
+
+    pub fn snapshot_on_drop(mut self, on_drop: bool) -> Self {
+        self.config.snapshot_on_drop = on_drop;
+        self
+    }
+
+    pub fn snapshot_every_ticks(mut self, ticks: u64) -> Self {
+        self.config.snapshot_every_ticks = Some(ticks);
+        self
+    }
+
+    pub fn snapshot_on_trade(mut self, on_trade: bool) -> Self {
+        self.config.snapshot_on_trade = on_trade;
+        self
+    }
+
+    pub fn snapshot_on_price_move(mut self, threshold: f32) -> Self {
+        self.config.snapshot_on_price_move = Some(threshold);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    pub fn token_mode(mut self, mode: TokenMode) -> Self {
+        self.config.token_mode = mode;
+        self
+    }
+
+    pub fn retired_token_ttl(mut self, ticks: u64) -> Self {
+        self.config.retired_token_ttl = ticks;
+        self
+    }
+
+    pub fn check_invariants(mut self, check: bool) -> Self {
+        self.config.check_invariants = check;
+        self
+    }
+
+    /// Validates the values set so far and returns the resulting config.
+    pub fn build(self) -> Result<MarketConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The config file couldn't be read.
+    Io { path: String, message: String },
+    /// The config file isn't valid JSON/TOML.
+    Parse { message: String },
+    /// The config file contains a key the market doesn't know about.
+    UnknownField { field: String },
+    /// A key of the config file holds a value of the wrong type.
+    InvalidType {
+        field: String,
+        expected: &'static str,
+    },
+    /// A setting is outside of its allowed range.
+    OutOfRange {
+        field: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, message } => {
+                write!(f, "couldn't read config file {}: {}", path, message)
+            }
+            ConfigError::Parse { message } => write!(f, "couldn't parse config: {}", message),
+            ConfigError::UnknownField { field } => write!(f, "unknown config field {}", field),
+            ConfigError::InvalidType { field, expected } => {
+                write!(f, "{} should be {}", field, expected)
+            }
+            ConfigError::OutOfRange {
+                field,
+                value,
+                expected,
+            } => write!(f, "{} is {}, expected {}", field, value, expected),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A template must name a file inside the snapshot directory once its placeholders are replaced.
+fn is_valid_snapshot_name_template(template: &str) -> bool {
+    let mut name = template.to_string();
+    for placeholder in SNAPSHOT_NAME_PLACEHOLDERS {
+        name = name.replace(placeholder, "x");
+    }
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['{', '}', '/', '\\'])
+}
+
+fn check_known_fields(
+    fields: &Map<String, Value>,
+    known: &[&str],
+    prefix: &str,
+) -> Result<(), ConfigError> {
+    for key in fields.keys() {
+        if !known.contains(&key.as_str()) {
+            return Err(ConfigError::UnknownField {
+                field: format!("{}{}", prefix, key),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn get_u64(
+    fields: &Map<String, Value>,
+    field: &str,
+    prefix: &str,
+) -> Result<Option<u64>, ConfigError> {
+    match fields.get(field) {
+        None => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or(ConfigError::InvalidType {
+            field: format!("{}{}", prefix, field),
+            expected: "a non-negative integer",
+        }),
+    }
+}
+
+fn get_f32(
+    fields: &Map<String, Value>,
+    field: &str,
+    prefix: &str,
+) -> Result<Option<f32>, ConfigError> {
+    match fields.get(field) {
+        None => Ok(None),
+        Some(value) => {
+            value
+                .as_f64()
+                .map(|number| Some(number as f32))
+                .ok_or(ConfigError::InvalidType {
+                    field: format!("{}{}", prefix, field),
+                    expected: "a number",
+                })
+        }
+    }
+}
+
+fn get_str<'a>(
+    fields: &'a Map<String, Value>,
+    field: &str,
+    prefix: &str,
+) -> Result<Option<&'a str>, ConfigError> {
+    match fields.get(field) {
+        None => Ok(None),
+        Some(value) => value.as_str().map(Some).ok_or(ConfigError::InvalidType {
+            field: format!("{}{}", prefix, field),
+            expected: "a string",
+        }),
+    }
+}
+
+fn get_bool(
+    fields: &Map<String, Value>,
+    field: &str,
+    prefix: &str,
+) -> Result<Option<bool>, ConfigError> {
+    match fields.get(field) {
+        None => Ok(None),
+        Some(value) => value.as_bool().map(Some).ok_or(ConfigError::InvalidType {
+            field: format!("{}{}", prefix, field),
+            expected: "true or false",
+        }),
+    }
+}
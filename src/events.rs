@@ -0,0 +1,46 @@
+use unitn_market_2022::good::good_kind::GoodKind;
+
+/// Market events that have no counterpart in `unitn_market_2022::event::event::EventKind`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FskEvent {
+    /// A buy lock was released by its trader, `quantity` of `good_kind` went back to the market.
+    LockBuyCancelled {
+        token: String,
+        good_kind: GoodKind,
+        quantity: f32,
+        price: f32,
+    },
+    /// A sell lock was released by its trader, `price` EUR went back to the market.
+    LockSellCancelled {
+        token: String,
+        good_kind: GoodKind,
+        quantity: f32,
+        price: f32,
+    },
+    /// A buy lock expired unclaimed, `quantity` of `good_kind` went back to the market.
+    LockBuyExpired {
+        token: String,
+        good_kind: GoodKind,
+        quantity: f32,
+        price: f32,
+    },
+    /// A sell lock expired unclaimed, `price` EUR went back to the market.
+    LockSellExpired {
+        token: String,
+        good_kind: GoodKind,
+        quantity: f32,
+        price: f32,
+    },
+    /// Black friday began, the buy exchange rates of every good but EUR were lowered by `discount`.
+    BlackFridayStarted { discount: f32 },
+    /// Black friday ended and the discount was taken back.
+    BlackFridayEnded { discount: f32 },
+    /// No trader interacted with the market for `inactive_ticks` ticks, the buy exchange rates of
+    /// every good but EUR were multiplied by `factor`.
+    RatesDecayed { factor: f32, inactive_ticks: u64 },
+}
+
+/// Subscriber for `FskEvent`s, see `FskMarket::add_fsk_subscriber`.
+pub trait FskNotifiable {
+    fn on_fsk_event(&mut self, event: FskEvent);
+}
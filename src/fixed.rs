@@ -0,0 +1,214 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+//decimal places kept by Fixed
+const DECIMALS: u32 = 9;
+
+const SCALE: i128 = 10_i128.pow(DECIMALS);
+
+/// Fixed-point decimal with 9 decimal places, used for every quantity, price and
+/// exchange rate the market keeps.
+///
+/// Additions and subtractions are exact, so goods moving between the market and its locks are
+/// never rounded. Multiplications and divisions round half to even, so repeated trades don't drift
+/// the rates in one direction and negative results round like positive ones. Every operation saturates at
+/// `Fixed::MAX` and `Fixed::MIN` instead of overflowing.
+///
+/// Snapshots store it as a decimal string, since JSON numbers are read as floats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fixed(i128);
+
+impl Fixed {
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(SCALE);
+    pub const MAX: Fixed = Fixed(i128::MAX);
+    pub const MIN: Fixed = Fixed(i128::MIN);
+
+    pub fn from_int(value: i64) -> Fixed {
+        Fixed(value as i128 * SCALE)
+    }
+
+    /// Closest value to `value`, NaN is 0 and infinities saturate.
+    pub fn from_f32(value: f32) -> Fixed {
+        Fixed::from_f64(value as f64)
+    }
+
+    /// Closest `f32` to the value, `Fixed::MAX` and `Fixed::MIN` become `f32::MAX` and `f32::MIN`.
+    pub fn to_f32(self) -> f32 {
+        match self {
+            Fixed::MAX => f32::MAX,
+            Fixed::MIN => f32::MIN,
+            _ => self.to_f64() as f32,
+        }
+    }
+
+    /// Closest value to `value`, NaN is 0 and infinities saturate.
+    pub(crate) fn from_f64(value: f64) -> Fixed {
+        //casts saturate and turn NaN into 0
+        Fixed((value * SCALE as f64).round() as i128)
+    }
+
+    fn to_f64(self) -> f64 {
+        self.0 as f64 / SCALE as f64
+    }
+
+    pub fn abs(self) -> Fixed {
+        Fixed(self.0.saturating_abs())
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, rhs: Fixed) -> Fixed {
+        Fixed(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, rhs: Fixed) -> Fixed {
+        Fixed(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        Fixed(self.0.saturating_neg())
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, rhs: Fixed) -> Fixed {
+        match self.0.checked_mul(rhs.0) {
+            Some(product) => Fixed(div_round(product, SCALE)),
+            //only values far beyond any reserve get here, they can afford the precision of a float
+            None => Fixed::from_f64(self.to_f64() * rhs.to_f64()),
+        }
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+
+    /// Dividing by zero saturates towards the sign of the dividend, 0 / 0 is 0.
+    fn div(self, rhs: Fixed) -> Fixed {
+        if rhs.0 == 0 {
+            return match self.0.signum() {
+                1 => Fixed::MAX,
+                -1 => Fixed::MIN,
+                _ => Fixed::ZERO,
+            };
+        }
+        match self.0.checked_mul(SCALE) {
+            Some(dividend) => Fixed(div_round(dividend, rhs.0)),
+            None => Fixed::from_f64(self.to_f64() / rhs.to_f64()),
+        }
+    }
+}
+
+/// `numerator / denominator` rounded half to even, whatever their signs.
+fn div_round(numerator: i128, denominator: i128) -> i128 {
+    let quotient = numerator / denominator;
+    //compare twice the remainder with the denominator to know which neighbour is closer
+    let twice_remainder = (numerator % denominator).unsigned_abs() * 2;
+    let denominator_abs = denominator.unsigned_abs();
+    let away = twice_remainder > denominator_abs
+        || (twice_remainder == denominator_abs && quotient % 2 != 0);
+    if !away {
+        quotient
+    } else if (numerator < 0) != (denominator < 0) {
+        quotient - 1
+    } else {
+        quotient + 1
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, rhs: Fixed) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, rhs: Fixed) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sum for Fixed {
+    fn sum<I: Iterator<Item = Fixed>>(iter: I) -> Fixed {
+        iter.fold(Fixed::ZERO, Add::add)
+    }
+}
+
+impl fmt::Display for Fixed {
+    /// Exact decimal representation, without trailing zeros.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = self.0.unsigned_abs() / SCALE as u128;
+        let decimals = self.0.unsigned_abs() % SCALE as u128;
+        if decimals == 0 {
+            return write!(f, "{}{}", sign, units);
+        }
+        let decimals = format!("{:0width$}", decimals, width = DECIMALS as usize);
+        write!(f, "{}{}.{}", sign, units, decimals.trim_end_matches('0'))
+    }
+}
+
+impl FromStr for Fixed {
+    type Err = String;
+
+    /// Parses a plain decimal like `-12.5`, with at most 9 decimal places.
+    fn from_str(s: &str) -> Result<Fixed, String> {
+        let invalid = || format!("{} is not a decimal number", s);
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        let (units, decimals) = digits.split_once('.').unwrap_or((digits, ""));
+        let all_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if units.is_empty() || !all_digits(units) || !all_digits(decimals) {
+            return Err(invalid());
+        }
+        if decimals.len() > DECIMALS as usize {
+            return Err(format!("{} has more than {} decimal places", s, DECIMALS));
+        }
+        let units: u128 = units.parse().map_err(|_| invalid())?;
+        let decimals: u128 = format!("{:0<width$}", decimals, width = DECIMALS as usize)
+            .parse()
+            .map_err(|_| invalid())?;
+        let out_of_range = || format!("{} is out of range", s);
+        let magnitude = units
+            .checked_mul(SCALE as u128)
+            .and_then(|value| value.checked_add(decimals))
+            .ok_or_else(out_of_range)?;
+        //Fixed::MIN has no positive counterpart
+        if negative && magnitude == i128::MIN.unsigned_abs() {
+            return Ok(Fixed::MIN);
+        }
+        let value = i128::try_from(magnitude).map_err(|_| out_of_range())?;
+        Ok(Fixed(if negative { -value } else { value }))
+    }
+}
+
+impl Serialize for Fixed {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Fixed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Fixed, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
@@ -1,17 +1,39 @@
 use chrono::Local;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 use serde::{Deserialize, Serialize};
+mod config;
+mod events;
+mod fixed;
+mod log;
+mod pricing;
+mod replay;
+mod snapshot;
 mod tests;
 
-use std::cell::RefCell;
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::fs::{create_dir_all, File, OpenOptions};
+pub use config::{ConfigError, InitialQuantities, MarketConfig, MarketConfigBuilder};
+pub use events::{FskEvent, FskNotifiable};
+pub use fixed::Fixed;
+pub use log::{
+    FileLogSink, LogFormat, LogOp, LogOutcome, LogRecord, LogSink, LogSinkMode, LoggedLock,
+    MarketState, MemoryLogSink, NullLogSink, StderrLogSink,
+};
+pub use pricing::{ConstantProductPricing, DefaultPricing, PricingMode, PricingStrategy, Reserve};
+pub use replay::{replay_file, replay_log, Divergence, ReplayError, ReplayReport};
+pub use snapshot::{SnapshotError, SNAPSHOT_VERSION};
+
+use snapshot::MarketSnapshot;
+
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fmt;
+use std::fs::{create_dir_all, remove_file, rename, OpenOptions};
 use std::rc::Rc;
 
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-use random_string::generate;
 use unitn_market_2022::event::event::{Event, EventKind};
 use unitn_market_2022::event::notifiable::Notifiable;
 use unitn_market_2022::good::consts::{
@@ -23,65 +45,449 @@ use unitn_market_2022::good::good_kind::GoodKind;
 use unitn_market_2022::market::good_label::GoodLabel;
 use unitn_market_2022::market::*;
 
-const LOCK_INITIAL_TTL: u64 = 9;
-//higher -> greedier
-const MARKET_GREEDINESS: f32 = 1.01;
+/// Result of `lock_buy_partial` and `lock_sell_partial`: the quantity and price actually locked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialLock {
+    pub token: String,
+    pub quantity: f32,
+    pub price: f32,
+}
 
-const BLACK_FRIDAY_DISCOUNT: f32 = 0.20; //discount the goods of 20%
+/// Reasons why `cancel_lock_buy` and `cancel_lock_sell` can refuse to release a lock.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CancelLockError {
+    UnrecognizedToken { unrecognized_token: String },
+    ExpiredToken { expired_token: String },
+    AlreadyCancelled { cancelled_token: String },
+    AlreadyConsumed { consumed_token: String },
+}
 
-const EXCHANGE_RATE_CHANGE_RATE_OVER_TIME: f32 = 0.999;
+/// Reasons why `extend_lock_buy` and `extend_lock_sell` can refuse to extend a lock.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtendLockError {
+    UnrecognizedToken { unrecognized_token: String },
+    ExpiredToken { expired_token: String },
+    CancelledToken { cancelled_token: String },
+    RenewalLimitReached { token: String, max_renewals: u32 },
+}
 
-const TRADER_INACTIVITY_TIME: u64 = 5;
+/// State of a lock after `extend_lock_buy` or `extend_lock_sell`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtendedLock {
+    pub expires_in: u64,
+    pub price: f32,
+    pub renewals: u32,
+}
 
-#[derive(Serialize, Deserialize)]
-struct MarketSnapshot {
-    goods: HashMap<GoodKind, GoodLabel>,
-    time: u64,
-    last_trader_interaction: u64,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockSide {
+    Buy,
+    Sell,
+}
+
+/// What the market knows about a token, see `FskMarket::token_status`.
+#[derive(Debug)]
+pub enum TokenStatus {
+    /// The lock can still be settled within `expires_in` ticks, for `price` EUR.
+    Active {
+        side: LockSide,
+        expires_in: u64,
+        good: Good,
+        price: f32,
+    },
+    Expired,
+    Consumed,
+    Cancelled,
+    Unknown,
+}
+
+/// A lock that can still be settled, see `FskMarket::open_locks`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenLock {
+    pub side: LockSide,
+    pub token: String,
+    pub trader_name: String,
+    pub good_kind: GoodKind,
+    pub quantity: f32,
+    pub price: f32,
+    pub expires_in: u64,
+}
+
+/// Lock caps of `MarketConfig` a trader would exceed with a new lock.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuotaError {
+    /// The trader already holds `max_locks` open locks.
+    TooManyLocks { trader_name: String, max_locks: u32 },
+    /// The trader would hold more than `max_quantity` of `good_kind` in open locks.
+    GoodQuantityLimit {
+        trader_name: String,
+        good_kind: GoodKind,
+        locked_quantity: f32,
+        requested_quantity: f32,
+        max_quantity: f32,
+    },
+    /// The prices of the open locks of the trader would add up to more than `max_eur`.
+    EurExposureLimit {
+        trader_name: String,
+        locked_eur: f32,
+        requested_eur: f32,
+        max_eur: f32,
+    },
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaError::TooManyLocks {
+                trader_name,
+                max_locks,
+            } => write!(f, "{} already holds {} open locks", trader_name, max_locks),
+            QuotaError::GoodQuantityLimit {
+                trader_name,
+                good_kind,
+                locked_quantity,
+                requested_quantity,
+                max_quantity,
+            } => write!(
+                f,
+                "{} holds {} {} in open locks, locking {} more would exceed {}",
+                trader_name, locked_quantity, good_kind, requested_quantity, max_quantity
+            ),
+            QuotaError::EurExposureLimit {
+                trader_name,
+                locked_eur,
+                requested_eur,
+                max_eur,
+            } => write!(
+                f,
+                "{} holds {} EUR in open locks, locking {} more would exceed {}",
+                trader_name, locked_eur, requested_eur, max_eur
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QuotaError {}
+
+impl QuotaError {
+    /// Quantity of the good a buy lock of `quantity` for `bid` EUR could still have, reported by
+    /// `Market::lock_buy` as `LockBuyError::InsufficientGoodQuantityAvailable`.
+    fn available_to_buy(&self, quantity: f32, bid: f32) -> f32 {
+        match self {
+            //no lock fits until one of the open ones is settled, cancelled or expires
+            QuotaError::TooManyLocks { .. } => 0.,
+            QuotaError::GoodQuantityLimit {
+                locked_quantity,
+                max_quantity,
+                ..
+            } => (max_quantity - locked_quantity).max(0.),
+            //the EUR left pay for the same share of the quantity at the price of the bid
+            QuotaError::EurExposureLimit {
+                locked_eur,
+                max_eur,
+                ..
+            } => (quantity * (max_eur - locked_eur) / bid).max(0.),
+        }
+    }
+
+    /// EUR a sell lock of `quantity` for `offer` EUR could still be paid, reported by
+    /// `Market::lock_sell` as `LockSellError::InsufficientDefaultGoodQuantityAvailable`.
+    fn available_to_sell(&self, quantity: f32, offer: f32) -> f32 {
+        match self {
+            QuotaError::TooManyLocks { .. } => 0.,
+            //the quantity left is worth the same share of the offer
+            QuotaError::GoodQuantityLimit {
+                locked_quantity,
+                max_quantity,
+                ..
+            } => (offer * (max_quantity - locked_quantity) / quantity).max(0.),
+            QuotaError::EurExposureLimit {
+                locked_eur,
+                max_eur,
+                ..
+            } => (max_eur - locked_eur).max(0.),
+        }
+    }
+}
+
+/// Error of the FSK-specific lock methods: one of the `Market` lock errors or a trader quota.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LockError<E> {
+    Market(E),
+    Quota(QuotaError),
+}
+
+/// Error of `FskMarket::buy_as` and `FskMarket::sell_as`: one of the `Market` settlement errors or a
+/// token locked by another trader.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettleError<E> {
+    Market(E),
+    WrongTrader { token: String, trader_name: String },
+}
+
+/// How lock tokens are drawn, see `MarketConfig::token_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TokenMode {
+    /// 10 lowercase alphanumeric characters, settled by anyone through `buy` and `sell`.
+    #[default]
+    Legacy,
+    /// 32 alphanumeric characters, settled only by the trader who locked them through `buy_as` and
+    /// `sell_as`. `buy` and `sell` can't tell who is settling: they refuse every token with
+    /// `UnrecognizedToken` and leave the lock open. For tokens this market issued the log records
+    /// `TraderBoundToken` as the reason.
+    TraderBound,
 }
 
+/// Reason logged when `buy` or `sell` refuse a token of `TokenMode::TraderBound`.
 #[derive(Debug)]
+struct TraderBoundToken;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct LockContract {
     token: String,
-    good: Good,
-    price: f32,
+    trader_name: String,
+    kind: GoodKind,
+    quantity: Fixed,
+    price: Fixed,
     expiry_time: u64,
+    renewals: u32,
 }
 
+#[derive(Clone, Default, Serialize, Deserialize)]
 struct ContractsArchive {
-    contracts_by_token: HashMap<String, Rc<LockContract>>,
-    expired_contracts: HashSet<String>,
-    contracts_by_timestamp: VecDeque<Rc<LockContract>>,
+    contracts_by_token: HashMap<String, LockContract>,
+    //retired tokens and the tick they were retired at
+    expired_contracts: HashMap<String, u64>,
+    consumed_contracts: HashMap<String, u64>,
+    cancelled_contracts: HashMap<String, u64>,
+    //min-heap of (expiry_time, token), entries of extended contracts are left behind and skipped
+    //not saved in snapshots, it's rebuilt from contracts_by_token by rebuild_indexes
+    #[serde(skip)]
+    contracts_by_expiry: BinaryHeap<Reverse<(u64, String)>>,
+    //(retired_at, token) in retirement order, forget_retired pops from the front
+    //not saved in snapshots either, it's rebuilt from the retired tokens by rebuild_indexes
+    #[serde(skip)]
+    retired_by_tick: VecDeque<(u64, String)>,
 }
 
 impl ContractsArchive {
     fn new() -> ContractsArchive {
         ContractsArchive {
             contracts_by_token: HashMap::new(),
-            expired_contracts: HashSet::new(),
-            contracts_by_timestamp: VecDeque::new(),
+            expired_contracts: HashMap::new(),
+            consumed_contracts: HashMap::new(),
+            cancelled_contracts: HashMap::new(),
+            contracts_by_expiry: BinaryHeap::new(),
+            retired_by_tick: VecDeque::new(),
         }
     }
 
-    fn new_token(&self) -> String {
-        let charset = "1234567890abcdefghijklmnopqrstuwxyz";
+    /// Rebuilds `contracts_by_expiry` and `retired_by_tick`, e.g. after loading a snapshot.
+    fn rebuild_indexes(&mut self) {
+        self.contracts_by_expiry = self
+            .contracts_by_token
+            .values()
+            .map(|contract| Reverse((contract.expiry_time, contract.token.clone())))
+            .collect();
+        let mut retired: Vec<(u64, String)> = self
+            .expired_contracts
+            .iter()
+            .chain(&self.consumed_contracts)
+            .chain(&self.cancelled_contracts)
+            .map(|(token, retired_at)| (*retired_at, token.clone()))
+            .collect();
+        retired.sort();
+        self.retired_by_tick = retired.into();
+    }
+
+    /// Draws a token this archive has never seen, live or retired.
+    fn new_token(&self, rng: &mut ChaCha12Rng, mode: TokenMode) -> String {
+        let (charset, length): (&[u8], usize) = match mode {
+            TokenMode::Legacy => (b"1234567890abcdefghijklmnopqrstuvwxyz", 10),
+            //ChaCha12 is a CSPRNG, 32 characters out of 62 can't be guessed
+            TokenMode::TraderBound => (
+                b"1234567890abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ",
+                32,
+            ),
+        };
         loop {
-            let res = generate(10, charset);
-            if !self.contracts_by_token.contains_key(&res) {
+            let res: String = (0..length)
+                .map(|_| charset[rng.gen_range(0..charset.len())] as char)
+                .collect();
+            let known = self.contracts_by_token.contains_key(&res)
+                || self.expired_contracts.contains_key(&res)
+                || self.consumed_contracts.contains_key(&res)
+                || self.cancelled_contracts.contains_key(&res);
+            if !known {
                 return res;
             }
         }
     }
 
-    fn add_contract(&mut self, contract: &Rc<LockContract>) {
+    fn add_contract(&mut self, contract: LockContract) {
+        self.contracts_by_expiry
+            .push(Reverse((contract.expiry_time, contract.token.clone())));
         //will always work since token is unique
         self.contracts_by_token
-            .insert(contract.token.clone(), contract.clone());
-        self.contracts_by_timestamp.push_back(contract.clone());
+            .insert(contract.token.clone(), contract);
+    }
+
+    fn consume_contract(&mut self, token: &String, timestamp: u64) -> Option<LockContract> {
+        let contract = self.contracts_by_token.remove(token);
+        if contract.is_some() {
+            self.consumed_contracts.insert(token.clone(), timestamp);
+            self.retired_by_tick.push_back((timestamp, token.clone()));
+        }
+        contract
+    }
+
+    /// Forgets the tokens retired `ttl` ticks ago or earlier, they are unrecognized from now on.
+    fn forget_retired(&mut self, timestamp: u64, ttl: u64) {
+        //tokens are retired as time goes on, so the oldest ones are always at the front
+        while let Some((retired_at, _)) = self.retired_by_tick.front() {
+            if *retired_at + ttl > timestamp {
+                break;
+            }
+            let (_, token) = self.retired_by_tick.pop_front().unwrap();
+            //a token is retired only once, so it's in exactly one of the three maps
+            self.expired_contracts.remove(&token);
+            self.consumed_contracts.remove(&token);
+            self.cancelled_contracts.remove(&token);
+        }
+    }
+
+    /// Status of `token` if it was issued by this archive.
+    fn token_status(&self, token: &str, side: LockSide, timestamp: u64) -> Option<TokenStatus> {
+        if let Some(contract) = self.contracts_by_token.get(token) {
+            if contract.expiry_time <= timestamp {
+                return Some(TokenStatus::Expired);
+            }
+            return Some(TokenStatus::Active {
+                side,
+                expires_in: contract.expiry_time - timestamp,
+                good: Good::new(contract.kind, contract.quantity.to_f32()),
+                price: contract.price.to_f32(),
+            });
+        }
+        if self.expired_contracts.contains_key(token) {
+            Some(TokenStatus::Expired)
+        } else if self.consumed_contracts.contains_key(token) {
+            Some(TokenStatus::Consumed)
+        } else if self.cancelled_contracts.contains_key(token) {
+            Some(TokenStatus::Cancelled)
+        } else {
+            None
+        }
+    }
+
+    fn open_locks(&self, side: LockSide, timestamp: u64) -> impl Iterator<Item = OpenLock> + '_ {
+        self.contracts_by_token
+            .values()
+            .filter(move |contract| contract.expiry_time > timestamp)
+            .map(move |contract| OpenLock {
+                side,
+                token: contract.token.clone(),
+                trader_name: contract.trader_name.clone(),
+                good_kind: contract.kind,
+                quantity: contract.quantity.to_f32(),
+                price: contract.price.to_f32(),
+                expires_in: contract.expiry_time - timestamp,
+            })
+    }
+
+    /// Contracts of `trader_name` that can still be settled.
+    fn trader_contracts<'a>(
+        &'a self,
+        trader_name: &'a str,
+        timestamp: u64,
+    ) -> impl Iterator<Item = &'a LockContract> + 'a {
+        self.contracts_by_token.values().filter(move |contract| {
+            contract.trader_name == trader_name && contract.expiry_time > timestamp
+        })
+    }
+
+    /// Removes a live contract on behalf of its trader and remembers its token as cancelled.
+    ///
+    /// It is the caller responsibility to restore resources contained in the returned contract.
+    fn cancel_contract(
+        &mut self,
+        token: &String,
+        timestamp: u64,
+    ) -> Result<LockContract, CancelLockError> {
+        if self.cancelled_contracts.contains_key(token) {
+            return Err(CancelLockError::AlreadyCancelled {
+                cancelled_token: token.clone(),
+            });
+        }
+        match self.contracts_by_token.get(token) {
+            Some(contract) if contract.expiry_time > timestamp => {
+                //the contract stays in contracts_by_expiry, pop_expired skips it as it's no longer in the hashmap
+                self.cancelled_contracts.insert(token.clone(), timestamp);
+                self.retired_by_tick.push_back((timestamp, token.clone()));
+                Ok(self.contracts_by_token.remove(token).unwrap())
+            }
+            Some(_) => Err(CancelLockError::ExpiredToken {
+                expired_token: token.clone(),
+            }),
+            None if self.expired_contracts.contains_key(token) => {
+                Err(CancelLockError::ExpiredToken {
+                    expired_token: token.clone(),
+                })
+            }
+            None if self.consumed_contracts.contains_key(token) => {
+                Err(CancelLockError::AlreadyConsumed {
+                    consumed_token: token.clone(),
+                })
+            }
+            None => Err(CancelLockError::UnrecognizedToken {
+                unrecognized_token: token.clone(),
+            }),
+        }
     }
 
-    fn consume_contract(&mut self, token: &String) -> Option<Rc<LockContract>> {
-        self.contracts_by_token.remove(token)
+    /// Pushes back the expiry of a live contract by `extension` ticks, at most `max_renewals` times.
+    fn extend_contract(
+        &mut self,
+        token: &String,
+        timestamp: u64,
+        extension: u64,
+        max_renewals: u32,
+    ) -> Result<&mut LockContract, ExtendLockError> {
+        if self.cancelled_contracts.contains_key(token) {
+            return Err(ExtendLockError::CancelledToken {
+                cancelled_token: token.clone(),
+            });
+        }
+        let contract = match self.contracts_by_token.get_mut(token) {
+            Some(contract) if contract.expiry_time > timestamp => contract,
+            Some(_) => {
+                return Err(ExtendLockError::ExpiredToken {
+                    expired_token: token.clone(),
+                })
+            }
+            None if self.expired_contracts.contains_key(token) => {
+                return Err(ExtendLockError::ExpiredToken {
+                    expired_token: token.clone(),
+                })
+            }
+            None => {
+                return Err(ExtendLockError::UnrecognizedToken {
+                    unrecognized_token: token.clone(),
+                })
+            }
+        };
+        if contract.renewals >= max_renewals {
+            return Err(ExtendLockError::RenewalLimitReached {
+                token: token.clone(),
+                max_renewals,
+            });
+        }
+        contract.renewals += 1;
+        contract.expiry_time += extension;
+        //the entry with the old expiry stays in the heap, pop_expired recognizes it as stale
+        self.contracts_by_expiry
+            .push(Reverse((contract.expiry_time, token.clone())));
+        Ok(contract)
     }
 
     /// This function returns an expired contract each time it's called.
@@ -90,24 +496,26 @@ impl ContractsArchive {
     /// It is the caller responsibility to restore resources contained in the returned contract.
     ///
     /// After all expired contracts have been popped, None is returned.
-    fn pop_expired(&mut self, timestamp: u64) -> Option<Rc<LockContract>> {
-        //While there are still contracts...
-        while let Some(contract_ref) = self.contracts_by_timestamp.front() {
-            let contract = contract_ref.clone();
-            //...and the first contract has expired...
-            if contract.expiry_time <= timestamp {
-                //...remove it from the contracts vector, as we don't need it anymore.
-                self.contracts_by_timestamp.pop_front();
-                //If the contract is still in the hashmap, it means that it has never been claimed, as buy and sell methods only remove claimed contracts from the hashmap.
-                if self.contracts_by_token.remove(&contract.token).is_some() {
-                    //If the contract has expired without being claimed, put it in the expired contracts set and return it.
-                    self.expired_contracts.insert(contract.token.clone());
-                    return Some(contract);
-                }
-                //If the contract is not in the hashmap, it means that it had been claimed. Let the 'while' cycle check the next contract in the vector.
-            } else {
+    fn pop_expired(&mut self, timestamp: u64) -> Option<LockContract> {
+        //While there are still contracts and the first one has expired...
+        while let Some(Reverse((expiry_time, _))) = self.contracts_by_expiry.peek() {
+            if *expiry_time > timestamp {
                 break;
             }
+            //...remove it from the heap, as we don't need it anymore.
+            let Reverse((expiry_time, token)) = self.contracts_by_expiry.pop().unwrap();
+            //If the contract is still in the hashmap with this expiry, it has never been claimed, cancelled or extended.
+            let still_locked = matches!(
+                self.contracts_by_token.get(&token),
+                Some(contract) if contract.expiry_time == expiry_time
+            );
+            if still_locked {
+                //If the contract has expired without being claimed, put it in the expired contracts set and return it.
+                self.expired_contracts.insert(token.clone(), timestamp);
+                self.retired_by_tick.push_back((timestamp, token.clone()));
+                return self.contracts_by_token.remove(&token);
+            }
+            //Otherwise the entry is stale. Let the 'while' cycle check the next entry in the heap.
         }
         //If we reached this statement, it means that all expired contracts have been cleared.
         None
@@ -115,490 +523,536 @@ impl ContractsArchive {
 }
 
 pub struct FskMarket {
-    goods: HashMap<GoodKind, GoodLabel>,
+    goods: HashMap<GoodKind, Reserve>,
     buy_contracts_archive: ContractsArchive,
     sell_contracts_archive: ContractsArchive,
     subs: Vec<Box<dyn Notifiable>>,
-    log_output: RefCell<File>,
+    fsk_subs: Vec<Box<dyn FskNotifiable>>,
+    log_sink: RefCell<Box<dyn LogSink>>,
+    //market time when the current log file was started, for log_rotate_every_ticks
+    log_started_at: Cell<u64>,
+    //length of the last entry written, for log_max_bytes
+    last_log_line_len: Cell<usize>,
+    //seeded from config.seed if set, draws every token. Saved in snapshots of seeded markets, so
+    //a restored market doesn't draw the same tokens again
+    rng: ChaCha12Rng,
     time: u64,
     last_trader_interaction: u64,
+    config: MarketConfig,
+    pricing: Box<dyn PricingStrategy>,
+    //(tick, file) of the snapshots named after the template, oldest first, for retention
+    snapshot_history: RefCell<Vec<(u64, PathBuf)>>,
+    //buy exchange rates at the last snapshot named after the template, for snapshot_on_price_move
+    snapshot_rates: RefCell<HashMap<GoodKind, Fixed>>,
+    //set by buy and sell, cleared by the on_event of their notification
+    trade_settled: bool,
+    //inventory plus locked reserves of every good, moved only by settlements, see check_invariants
+    expected_totals: HashMap<GoodKind, Fixed>,
 }
 
 impl FskMarket {
-    fn notify(&mut self, event: Event) {
-        self.on_event(event.clone());
-        for sub in &mut self.subs {
-            sub.on_event(event.clone());
-        }
+    /// Creates a market with the given quantities whose behaviour is driven by `config`
+    /// instead of the default values.
+    pub fn with_config(
+        eur: f32,
+        yen: f32,
+        usd: f32,
+        yuan: f32,
+        config: MarketConfig,
+    ) -> Rc<RefCell<FskMarket>> {
+        let pricing = config.pricing_mode.strategy();
+        FskMarket::with_pricing(eur, yen, usd, yuan, config, pricing)
     }
 
-    fn restore_all_lock_contracts(&mut self) -> HashMap<GoodKind, f32> {
-        //remove buy contracts
-        let mut old_quantities: HashMap<GoodKind, f32> = HashMap::new();
-        for (_, buy_contract) in &self.buy_contracts_archive.contracts_by_token {
-            let gk = buy_contract.good.get_kind();
-            //save old quantity for return value
-            let old_qty = self.goods.get(&gk).unwrap().quantity;
-            old_quantities.insert(gk, old_qty);
-            //these unwraps shouldn't fail!
-            let new_gl = GoodLabel {
-                good_kind: gk,
-                quantity: self.goods.get(&gk).unwrap().quantity + buy_contract.good.get_qty(),
-                exchange_rate_buy: self.goods.get(&gk).unwrap().exchange_rate_buy,
-                exchange_rate_sell: self.goods.get(&gk).unwrap().exchange_rate_sell,
-            };
-            //add the lock quantity back to the market
-            self.goods.insert(buy_contract.good.get_kind(), new_gl);
-        }
-        //remove sell contracts
-        for (_, sell_contract) in &self.sell_contracts_archive.contracts_by_token {
-            let gk = sell_contract.good.get_kind();
-            //these unwraps shouldn't fail!
-            let new_gl = GoodLabel {
-                good_kind: gk,
-                quantity: self.goods.get(&gk).unwrap().quantity + sell_contract.good.get_qty(),
-                exchange_rate_buy: self.goods.get(&gk).unwrap().exchange_rate_buy,
-                exchange_rate_sell: self.goods.get(&gk).unwrap().exchange_rate_sell,
-            };
-            //add the lock quantity back to the market
-            self.goods.insert(sell_contract.good.get_kind(), new_gl);
-        }
-        old_quantities
+    /// Like `with_config`, but prices goods through the given strategy instead of the one selected
+    /// by `config.pricing_mode()`.
+    pub fn with_pricing(
+        eur: f32,
+        yen: f32,
+        usd: f32,
+        yuan: f32,
+        config: MarketConfig,
+        pricing: Box<dyn PricingStrategy>,
+    ) -> Rc<RefCell<FskMarket>> {
+        let log_sink = FskMarket::open_log_sink(&config);
+        FskMarket::build(eur, yen, usd, yuan, config, pricing, log_sink)
     }
 
-    fn update_prices(&mut self, old_quantities: HashMap<GoodKind, f32>) {
-        for (gk, qty) in &old_quantities {
-            if *gk != GoodKind::EUR {
-                //calculate new exchange_rate_buy given the quantity bought
-                let new_exchange_rate_buy = FskMarket::get_new_exchange_rate_buy(
-                    self.goods.get(gk).unwrap().exchange_rate_buy,
-                    self.goods.get(gk).unwrap().quantity,
-                    self.goods.get(gk).unwrap().quantity - qty,
-                );
-                self.goods.get_mut(gk).unwrap().exchange_rate_buy = new_exchange_rate_buy;
-                //calculate new exchange_rate_sell given the new exchange_rate_buy
-                let new_exchange_rate_sell =
-                    FskMarket::get_new_exchange_rate_sell(new_exchange_rate_buy);
-                self.goods.get_mut(gk).unwrap().exchange_rate_sell = new_exchange_rate_sell;
-            }
+    /// Like `with_config`, but logs to the given sink instead of the one selected by
+    /// `config.log_sink()`.
+    pub fn with_log_sink(
+        eur: f32,
+        yen: f32,
+        usd: f32,
+        yuan: f32,
+        config: MarketConfig,
+        log_sink: Box<dyn LogSink>,
+    ) -> Rc<RefCell<FskMarket>> {
+        let pricing = config.pricing_mode.strategy();
+        FskMarket::build(eur, yen, usd, yuan, config, pricing, log_sink)
+    }
+
+    fn build(
+        eur: f32,
+        yen: f32,
+        usd: f32,
+        yuan: f32,
+        config: MarketConfig,
+        pricing: Box<dyn PricingStrategy>,
+        log_sink: Box<dyn LogSink>,
+    ) -> Rc<RefCell<FskMarket>> {
+        let greediness = Fixed::from_f32(config.market_greediness);
+        let mut goods_result = HashMap::new();
+        let reserves = [
+            (GoodKind::EUR, eur, 1.),
+            (GoodKind::YEN, yen, DEFAULT_EUR_YEN_EXCHANGE_RATE),
+            (GoodKind::USD, usd, DEFAULT_EUR_USD_EXCHANGE_RATE),
+            (GoodKind::YUAN, yuan, DEFAULT_EUR_YUAN_EXCHANGE_RATE),
+        ];
+        for (good_kind, quantity, eur_exchange_rate) in reserves {
+            let exchange_rate_buy = Fixed::ONE / Fixed::from_f32(eur_exchange_rate);
+            goods_result.insert(
+                good_kind,
+                Reserve {
+                    good_kind,
+                    quantity: Fixed::from_f32(quantity),
+                    exchange_rate_buy,
+                    exchange_rate_sell: exchange_rate_buy / greediness,
+                },
+            );
         }
+
+        //let the strategy derive the starting rates from the reserves if it needs to
+        let eur_reserve = Fixed::from_f32(eur);
+        for reserve in goods_result.values_mut() {
+            pricing.refresh_rates(reserve, eur_reserve, greediness);
+        }
+
+        let snapshot_rates = FskMarket::buy_rates(&goods_result);
+        let new_market = Rc::new(RefCell::new(FskMarket {
+            goods: goods_result,
+            buy_contracts_archive: ContractsArchive::new(),
+            sell_contracts_archive: ContractsArchive::new(),
+            subs: vec![],
+            fsk_subs: vec![],
+            log_sink: RefCell::new(log_sink),
+            log_started_at: Cell::new(0),
+            last_log_line_len: Cell::new(0),
+            rng: FskMarket::new_rng(&config),
+            snapshot_history: RefCell::new(vec![]),
+            snapshot_rates: RefCell::new(snapshot_rates),
+            trade_settled: false,
+            expected_totals: HashMap::new(),
+            time: 0,
+            last_trader_interaction: 0,
+            config,
+            pricing,
+        }));
+        let expected_totals = new_market.borrow().reserve_totals();
+        new_market.borrow_mut().expected_totals = expected_totals;
+        //log market init
+        new_market.borrow().write_log_market_init();
+        //take the first snapshot
+        //new_market.borrow().take_snapshot(String::new());
+        //return it
+        new_market
     }
 
-    fn update_price(&mut self, gk: &GoodKind, qty: f32) {
-        //calculate new exchange_rate_buy given the quantity bought
-        let new_exchange_rate_buy = FskMarket::get_new_exchange_rate_buy(
-            self.goods.get(gk).unwrap().exchange_rate_buy,
-            self.goods.get(gk).unwrap().quantity + qty,
-            qty,
-        );
-        self.goods.get_mut(gk).unwrap().exchange_rate_buy = new_exchange_rate_buy;
-        //calculate new exchange_rate_sell given the new exchange_rate_buy
-        let new_exchange_rate_sell = FskMarket::get_new_exchange_rate_sell(new_exchange_rate_buy);
-        self.goods.get_mut(gk).unwrap().exchange_rate_sell = new_exchange_rate_sell;
+    /// Creates a market from a JSON/TOML config file, see `MarketConfig::from_file`.
+    ///
+    /// If the file doesn't set `initial_quantities` the starting capital is allocated randomly.
+    pub fn from_config_file<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<Rc<RefCell<FskMarket>>, ConfigError> {
+        let config = MarketConfig::from_file(path)?;
+        let quantities = config
+            .initial_quantities
+            .unwrap_or_else(|| FskMarket::random_quantities(&mut FskMarket::new_rng(&config)));
+        Ok(FskMarket::with_config(
+            quantities.eur,
+            quantities.yen,
+            quantities.usd,
+            quantities.yuan,
+            config,
+        ))
+    }
+
+    /// Restores a market from a snapshot written by `take_snapshot`, open locks included.
+    pub fn try_from_snapshot<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<Rc<RefCell<FskMarket>>, SnapshotError> {
+        FskMarket::try_from_snapshot_with_config(path, MarketConfig::default())
+    }
+
+    /// Like `try_from_snapshot`, with the given config instead of the default one.
+    ///
+    /// `initial_quantities` is ignored, the quantities come from the snapshot.
+    pub fn try_from_snapshot_with_config<P: AsRef<Path>>(
+        path: P,
+        config: MarketConfig,
+    ) -> Result<Rc<RefCell<FskMarket>>, SnapshotError> {
+        let log_sink = FskMarket::open_log_sink(&config);
+        FskMarket::try_from_snapshot_with_log_sink(path, config, log_sink)
+    }
+
+    /// Like `try_from_snapshot_with_config`, but logs to the given sink instead of the one
+    /// selected by `config.log_sink()`.
+    pub fn try_from_snapshot_with_log_sink<P: AsRef<Path>>(
+        path: P,
+        config: MarketConfig,
+        log_sink: Box<dyn LogSink>,
+    ) -> Result<Rc<RefCell<FskMarket>>, SnapshotError> {
+        let snapshot = MarketSnapshot::from_file(path.as_ref())?;
+        let new_market = FskMarket::from_snapshot(snapshot, config, log_sink);
+        //log the restored state, so the log can be replayed from here
+        new_market.borrow().write_log_market_state();
+        //take the first snapshot
+        new_market.borrow().take_snapshot(String::new());
+        Ok(new_market)
     }
 
-    fn take_snapshot(&self, mut filename: String) {
-        if filename.is_empty() {
-            filename = format!("snapshots/market_FSK_snapshot_{}.json", self.time)
+    /// Market in the state of `snapshot`, without logging or snapshotting anything.
+    fn from_snapshot(
+        snapshot: MarketSnapshot,
+        config: MarketConfig,
+        log_sink: Box<dyn LogSink>,
+    ) -> Rc<RefCell<FskMarket>> {
+        let snapshot_rates = FskMarket::buy_rates(&snapshot.goods);
+        let new_market = Rc::new(RefCell::new(FskMarket {
+            goods: snapshot.goods,
+            buy_contracts_archive: snapshot.buy_contracts_archive,
+            sell_contracts_archive: snapshot.sell_contracts_archive,
+            subs: vec![],
+            fsk_subs: vec![],
+            log_sink: RefCell::new(log_sink),
+            log_started_at: Cell::new(snapshot.time),
+            last_log_line_len: Cell::new(0),
+            //a seeded market carries on with the generator it was snapshotted with
+            rng: match (config.seed, snapshot.rng) {
+                (Some(_), Some(rng)) => rng,
+                _ => FskMarket::new_rng(&config),
+            },
+            snapshot_history: RefCell::new(vec![]),
+            snapshot_rates: RefCell::new(snapshot_rates),
+            trade_settled: false,
+            expected_totals: HashMap::new(),
+            time: snapshot.time,
+            last_trader_interaction: snapshot.last_trader_interaction,
+            pricing: config.pricing_mode.strategy(),
+            config,
+        }));
+        let expected_totals = new_market.borrow().reserve_totals();
+        new_market.borrow_mut().expected_totals = expected_totals;
+        new_market
+    }
+
+    /// Market in the state logged at the start of a log, see `LogRecord::state`.
+    pub(crate) fn from_logged_state(
+        state: &MarketState,
+        config: MarketConfig,
+        log_sink: Box<dyn LogSink>,
+    ) -> Rc<RefCell<FskMarket>> {
+        let mut archives = [ContractsArchive::new(), ContractsArchive::new()];
+        for lock in &state.locks {
+            let archive = match lock.side {
+                LockSide::Buy => &mut archives[0],
+                LockSide::Sell => &mut archives[1],
+            };
+            archive.add_contract(LockContract {
+                token: lock.token.clone(),
+                trader_name: lock.trader_name.clone(),
+                kind: lock.kind,
+                quantity: lock.quantity,
+                price: lock.price,
+                expiry_time: lock.expiry_time,
+                renewals: lock.renewals,
+            });
         }
-        //copy market values to save to market snapshot
+        let [buy_contracts_archive, sell_contracts_archive] = archives;
         let snapshot = MarketSnapshot {
-            goods: self.goods.clone(),
-            time: self.time,
-            last_trader_interaction: self.last_trader_interaction,
+            version: SNAPSHOT_VERSION,
+            goods: state
+                .goods
+                .iter()
+                .map(|reserve| (reserve.good_kind, reserve.clone()))
+                .collect(),
+            time: state.tick,
+            last_trader_interaction: state.last_trader_interaction,
+            buy_contracts_archive,
+            sell_contracts_archive,
+            rng: None,
         };
-        let json_parser_result = serde_json::to_string(&snapshot);
-        if let Ok(snapshot_json) = json_parser_result {
-            if let Err(err) = create_dir_all("snapshots") {
-                println!(
-                    "Couldn't create snapshot directory, check error below:\n{:?}",
-                    err
-                );
-            } else {
-                //directory created succesfully
-                let file_res = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(filename);
-                if let Ok(mut file) = file_res {
-                    if let Err(err) = file.write(snapshot_json.as_bytes()) {
-                        println!(
-                            "Couldn't write in snapshot file, check error below:\n{:?}",
-                            err
-                        );
-                    }
-                } else if let Err(err) = file_res {
-                    println!(
-                        "Couldn't create snapshot file, check error below:\n{:?}",
-                        err
-                    );
-                }
-            }
-        } else if let Err(err) = json_parser_result {
-            println!("Serde couldn't parse the market snapshot:\n{:?}", err);
-        }
+        FskMarket::from_snapshot(snapshot, config, log_sink)
     }
 
-    fn initialize_log_file(market_name: String) -> RefCell<File> {
-        let log_file_name = format!("log_{}.txt", market_name);
-        RefCell::new(
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(log_file_name)
-                .unwrap(),
+    /// Like `Market::new_random`, but the allocation and every token are drawn from a generator
+    /// seeded with `seed`, see `MarketConfig::seed`.
+    pub fn new_random_seeded(seed: u64) -> Rc<RefCell<FskMarket>> {
+        FskMarket::new_random_with_config(MarketConfig::builder().seed(seed).build().unwrap())
+    }
+
+    /// Like `Market::new_random`, configured by `config`. The allocation is reproducible if
+    /// `MarketConfig::seed` is set.
+    pub fn new_random_with_config(config: MarketConfig) -> Rc<RefCell<FskMarket>> {
+        let quantities = FskMarket::random_quantities(&mut FskMarket::new_rng(&config));
+        FskMarket::with_config(
+            quantities.eur,
+            quantities.yen,
+            quantities.usd,
+            quantities.yuan,
+            config,
         )
     }
 
-    fn write_log_entry(&self, entry: String) {
-        if self
-            .log_output
-            .borrow_mut()
-            .write(
-                format!(
-                    "{}|{}|{}\n",
-                    self.get_name(),
-                    Local::now().format("%y:%m:%d:%H:%M:%S:%3f"),
-                    entry
-                )
-                .as_bytes(),
-            )
-            .is_err()
-        {
-            println!("{}: Couldn't write to log file", self.get_name())
+    fn new_rng(config: &MarketConfig) -> ChaCha12Rng {
+        match config.seed {
+            Some(seed) => ChaCha12Rng::seed_from_u64(seed),
+            None => ChaCha12Rng::from_entropy(),
         }
-        //YY:MM:DD:HH:MM:SEC:MSES
     }
 
-    fn write_log_market_init(&self) {
-        self.write_log_entry(format!("\nMARKET_INITIALIZATION\nEUR: {:+e}\nUSD: {:+e}\nYEN: {:+e}\nYUAN: {:+e}\nEND_MARKET_INITIALIZATION",
-            self.goods.get(&GoodKind::EUR).unwrap().quantity,
-            self.goods.get(&GoodKind::USD).unwrap().quantity,
-            self.goods.get(&GoodKind::YEN).unwrap().quantity,
-            self.goods.get(&GoodKind::YUAN).unwrap().quantity
-        ));
-    }
+    fn random_quantities<R: Rng>(rng: &mut R) -> InitialQuantities {
+        //-0.1 to make up for float rounding errors
+        let mut remainder = STARTING_CAPITAL - 0.1;
 
-    fn write_log_buy_ok(
-        &self,
-        trader_name: String,
-        kind_to_buy: GoodKind,
-        quantity_to_buy: f32,
-        bid: f32,
-        token: &String,
-    ) {
-        self.write_log_entry(format!(
-            "LOCK_BUY-{}-KIND_TO_BUY:{}-QUANTITY_TO_BUY:{:+e}-BID:{:+e}-TOKEN:{}",
-            trader_name, kind_to_buy, quantity_to_buy, bid, token
-        ));
-    }
+        let mut temp = rng.gen_range(0..remainder as i32);
+        let yen_qty = temp as f32 * DEFAULT_EUR_YEN_EXCHANGE_RATE;
+        remainder -= temp as f32;
 
-    fn write_log_lock_buy_error(
-        &self,
-        trader_name: String,
-        kind_to_buy: GoodKind,
-        quantity_to_buy: f32,
-        bid: f32,
-    ) {
-        self.write_log_entry(format!(
-            "LOCK_BUY-{}-KIND_TO_BUY:{}-QUANTITY_TO_BUY:{:+e}-BID:{:+e}-ERROR",
-            trader_name, kind_to_buy, quantity_to_buy, bid
-        ));
-    }
+        temp = rng.gen_range(0..remainder as i32);
+        let usd_qty = temp as f32 * DEFAULT_EUR_USD_EXCHANGE_RATE;
+        remainder -= temp as f32;
 
-    fn write_log_sell_ok(
-        &self,
-        trader_name: String,
-        kind_to_sell: GoodKind,
-        quantity_to_sell: f32,
-        offer: f32,
-        token: &String,
-    ) {
-        self.write_log_entry(format!(
-            "LOCK_SELL-{}-KIND_TO_SELL:{}-QUANTITY_TO_SELL:{:+e}-OFFER:{:+e}-TOKEN:{}",
-            trader_name, kind_to_sell, quantity_to_sell, offer, token
-        ));
+        temp = rng.gen_range(0..remainder as i32);
+        let yuan_qty = temp as f32 * DEFAULT_EUR_YUAN_EXCHANGE_RATE;
+        remainder -= temp as f32;
+
+        let eur_qty = remainder;
+        InitialQuantities {
+            eur: eur_qty,
+            yen: yen_qty,
+            usd: usd_qty,
+            yuan: yuan_qty,
+        }
     }
 
-    fn write_log_lock_sell_error(
-        &self,
-        trader_name: String,
-        kind_to_sell: GoodKind,
-        quantity_to_sell: f32,
-        offer: f32,
-    ) {
-        self.write_log_entry(format!(
-            "LOCK_SELL-{}-KIND_TO_SELL:{}-QUANTITY_TO_SELL:{:+e}-OFFER:{:+e}-ERROR",
-            trader_name, kind_to_sell, quantity_to_sell, offer
-        ));
+    pub fn config(&self) -> &MarketConfig {
+        &self.config
     }
 
-    fn write_log_sell_error(&self, token: &String) {
-        self.write_log_entry(format!("SELL-TOKEN:{}-ERROR", token));
+    /// Tells whether `token` can still be settled, and if not why.
+    ///
+    /// Buy locks are looked up first: in the unlikely case a buy and a sell lock share a token,
+    /// the buy lock is reported.
+    pub fn token_status(&self, token: &str) -> TokenStatus {
+        self.buy_contracts_archive
+            .token_status(token, LockSide::Buy, self.time)
+            .or_else(|| {
+                self.sell_contracts_archive
+                    .token_status(token, LockSide::Sell, self.time)
+            })
+            .unwrap_or(TokenStatus::Unknown)
     }
 
-    fn write_log_buy_error(&self, token: &String) {
-        self.write_log_entry(format!("BUY-TOKEN:{}-ERROR", token));
+    /// Every lock that can still be settled, buy locks first, each side sorted by expiry.
+    pub fn open_locks(&self) -> Vec<OpenLock> {
+        let mut buy_locks: Vec<OpenLock> = self
+            .buy_contracts_archive
+            .open_locks(LockSide::Buy, self.time)
+            .collect();
+        let mut sell_locks: Vec<OpenLock> = self
+            .sell_contracts_archive
+            .open_locks(LockSide::Sell, self.time)
+            .collect();
+        for locks in [&mut buy_locks, &mut sell_locks] {
+            locks.sort_by(|a, b| {
+                a.expires_in
+                    .cmp(&b.expires_in)
+                    .then_with(|| a.token.cmp(&b.token))
+            });
+        }
+        buy_locks.append(&mut sell_locks);
+        buy_locks
     }
 
-    fn get_new_exchange_rate_sell(exchange_rate_buy: f32) -> f32 {
-        exchange_rate_buy / MARKET_GREEDINESS
+    /// Locks of `trader_name` that can still be settled, in the same order as `open_locks`.
+    pub fn open_locks_of(&self, trader_name: &str) -> Vec<OpenLock> {
+        self.open_locks()
+            .into_iter()
+            .filter(|lock| lock.trader_name == trader_name)
+            .collect()
     }
 
-    fn get_new_exchange_rate_buy(
-        current_exchange_rate_buy: f32,
-        current_quantity: f32,
-        quantity_to_buy: f32,
-    ) -> f32 {
-        let den = current_quantity - quantity_to_buy;
-        if den == 0. {
-            return f32::MAX;
+    /// Checks that a new lock of `quantity` of `kind` for `price` EUR keeps `trader_name` within
+    /// the lock caps of the config.
+    fn check_lock_quota(
+        &self,
+        trader_name: &str,
+        kind: GoodKind,
+        quantity: Fixed,
+        price: Fixed,
+    ) -> Result<(), QuotaError> {
+        //buy and sell locks count the same towards the caps
+        let contracts: Vec<&LockContract> = self
+            .buy_contracts_archive
+            .trader_contracts(trader_name, self.time)
+            .chain(
+                self.sell_contracts_archive
+                    .trader_contracts(trader_name, self.time),
+            )
+            .collect();
+        if let Some(max_locks) = self.config.max_locks_per_trader {
+            if contracts.len() as u64 >= max_locks as u64 {
+                return Err(QuotaError::TooManyLocks {
+                    trader_name: trader_name.to_string(),
+                    max_locks,
+                });
+            }
+        }
+        if let Some(max_quantity) = self.config.max_locked_quantity_per_good {
+            let locked_quantity: Fixed = contracts
+                .iter()
+                .filter(|contract| contract.kind == kind)
+                .map(|contract| contract.quantity)
+                .sum();
+            if locked_quantity + quantity > Fixed::from_f32(max_quantity) {
+                return Err(QuotaError::GoodQuantityLimit {
+                    trader_name: trader_name.to_string(),
+                    good_kind: kind,
+                    locked_quantity: locked_quantity.to_f32(),
+                    requested_quantity: quantity.to_f32(),
+                    max_quantity,
+                });
+            }
+        }
+        if let Some(max_eur) = self.config.max_locked_eur_per_trader {
+            let locked_eur: Fixed = contracts.iter().map(|contract| contract.price).sum();
+            if locked_eur + price > Fixed::from_f32(max_eur) {
+                return Err(QuotaError::EurExposureLimit {
+                    trader_name: trader_name.to_string(),
+                    locked_eur: locked_eur.to_f32(),
+                    requested_eur: price.to_f32(),
+                    max_eur,
+                });
+            }
         }
-        current_exchange_rate_buy * current_quantity / den
+        Ok(())
     }
-}
 
-impl Drop for FskMarket {
-    fn drop(&mut self) {
-        //removes all the locks and restore all good quantities before snapshot
-        let old_quantities = self.restore_all_lock_contracts();
-        //update prices accordingly
-        self.update_prices(old_quantities);
-        //take snapshot
-        self.take_snapshot("snapshots/market_FSK_snapshot_at_drop.json".to_string())
+    /// Subscribes to the events that `Notifiable` subscribers can't receive, like lock cancellations.
+    pub fn add_fsk_subscriber(&mut self, subscriber: Box<dyn FskNotifiable>) {
+        self.fsk_subs.push(subscriber);
     }
-}
 
-impl Notifiable for FskMarket {
-    fn add_subscriber(&mut self, subscriber: Box<dyn Notifiable>) {
-        self.subs.push(subscriber);
+    fn notify(&mut self, event: Event) {
+        self.on_event(event.clone());
+        for sub in &mut self.subs {
+            sub.on_event(event.clone());
+        }
     }
 
-    fn on_event(&mut self, event: Event) {
-        // here we apply logic of changing good quantities, as described in https://github.com/orgs/WG-AdvancedProgramming/discussions/38#discussioncomment-4167913
-        //every event triggers a new tick
-        self.time += 1;
-        /*         match event.kind {
-            EventKind::LockedBuy => {}
-            EventKind::Bought => {}
-            EventKind::LockedSell => {}
-            EventKind::Sold => {}
-            EventKind::Wait => {}
-        } */
-
-        //check when last trader interaction in our market was
-        if self.time - self.last_trader_interaction > TRADER_INACTIVITY_TIME {
-            //if it was too long ago, we decrease exchange rates
-            //decrease exchange rate over time
-            for (good_kind, good_label) in &mut self.goods {
-                match *good_kind {
-                    DEFAULT_GOOD_KIND => {}
-                    _ => {
-                        good_label.exchange_rate_buy *= EXCHANGE_RATE_CHANGE_RATE_OVER_TIME;
-                        good_label.exchange_rate_sell =
-                            FskMarket::get_new_exchange_rate_sell(good_label.exchange_rate_buy)
-                    }
-                }
-            }
-        }
-
-        //restore locked default currency for expired sell
-        while let Some(expired_contract) = self.sell_contracts_archive.pop_expired(self.time) {
-            /* contract_expired_type = 1;
-            expired_gk = DEFAULT_GOOD_KIND; */
-            self.goods.get_mut(&DEFAULT_GOOD_KIND).unwrap().quantity += expired_contract.price;
-        }
-
-        //restore locked good for expired buyout
-        while let Some(expired_contract) = self.buy_contracts_archive.pop_expired(self.time) {
-            /* contract_expired_type = 2;
-            expired_gk = expired_contract.good.get_kind(); */
-            self.goods
-                .get_mut(&expired_contract.good.get_kind())
-                .unwrap()
-                .quantity += expired_contract.good.get_qty();
-        }
-
-        //black_friday_handling
-        //black friday begins
-        if self.time % 7 == 4 {
-            for (good_kind, good_label) in &mut self.goods {
-                match *good_kind {
-                    DEFAULT_GOOD_KIND => {}
-                    _ => {
-                        good_label.exchange_rate_buy *= 1. - BLACK_FRIDAY_DISCOUNT;
-                        good_label.exchange_rate_sell =
-                            FskMarket::get_new_exchange_rate_sell(good_label.exchange_rate_buy)
-                    }
-                }
-            }
-        }
-        //black friday ends
-        if self.time % 7 == 5 {
-            for (good_kind, good_label) in &mut self.goods {
-                match *good_kind {
-                    DEFAULT_GOOD_KIND => {}
-                    _ => {
-                        good_label.exchange_rate_buy /= 1. - BLACK_FRIDAY_DISCOUNT;
-                        good_label.exchange_rate_sell =
-                            FskMarket::get_new_exchange_rate_sell(good_label.exchange_rate_buy)
-                    }
-                }
-            }
+    fn notify_fsk(&mut self, event: FskEvent) {
+        for sub in &mut self.fsk_subs {
+            sub.on_fsk_event(event.clone());
         }
-
-        //take snapshot and save to file for visualizer
-        //self.take_snapshot(String::new());
     }
-}
 
-#[allow(unused_must_use)]
-impl Market for FskMarket {
-    fn new_random() -> Rc<RefCell<dyn Market>>
-    where
-        Self: Sized,
-    {
-        let mut rng = rand::thread_rng();
-        //-0.1 to make up for float rounding errors
-        let mut remainder = STARTING_CAPITAL - 0.1;
+    /// Releases a buy lock before it expires: the locked good goes back to the market right away.
+    ///
+    /// Settling a cancelled token with `buy` fails with `BuyError::ExpiredToken`.
+    ///
+    /// Only `FskNotifiable` subscribers are told, with `FskEvent::LockBuyCancelled`: `EventKind`
+    /// has no cancellation for `Notifiable` subscribers, and cancelling doesn't move the market time.
+    pub fn cancel_lock_buy(&mut self, token: String) -> Result<(), CancelLockError> {
+        let contract = match self
+            .buy_contracts_archive
+            .cancel_contract(&token, self.time)
+        {
+            Ok(contract) => contract,
+            Err(err) => {
+                self.write_log(LogRecord::token_op(
+                    LogOp::CancelLockBuy,
+                    &token,
+                    Some(&err),
+                ));
+                return Err(err);
+            }
+        };
 
-        let mut temp = rng.gen_range(0..remainder as i32);
-        let yen_qty = temp as f32 * DEFAULT_EUR_YEN_EXCHANGE_RATE;
-        remainder -= temp as f32;
+        //put the locked good back in the market
+        self.goods.get_mut(&contract.kind).unwrap().quantity += contract.quantity;
 
-        temp = rng.gen_range(0..remainder as i32);
-        let usd_qty = temp as f32 * DEFAULT_EUR_USD_EXCHANGE_RATE;
-        remainder -= temp as f32;
+        //log
+        self.write_log(LogRecord::token_op(LogOp::CancelLockBuy, &token, None));
 
-        temp = rng.gen_range(0..remainder as i32);
-        let yuan_qty = temp as f32 * DEFAULT_EUR_YUAN_EXCHANGE_RATE;
-        remainder -= temp as f32;
+        //save this interaction
+        self.last_trader_interaction = self.time;
 
-        let eur_qty = remainder;
-        FskMarket::new_with_quantities(eur_qty, yen_qty, usd_qty, yuan_qty)
+        self.notify_fsk(FskEvent::LockBuyCancelled {
+            token,
+            good_kind: contract.kind,
+            quantity: contract.quantity.to_f32(),
+            price: contract.price.to_f32(),
+        });
+        self.finish_operation("cancel_lock_buy");
+        Ok(())
     }
 
-    fn new_with_quantities(eur: f32, yen: f32, usd: f32, yuan: f32) -> Rc<RefCell<dyn Market>>
-    where
-        Self: Sized,
-    {
-        let mut goods_result = HashMap::new();
-        goods_result.insert(
-            GoodKind::EUR,
-            GoodLabel {
-                good_kind: GoodKind::EUR,
-                quantity: eur,
-                exchange_rate_buy: 1.,
-                exchange_rate_sell: 1. / MARKET_GREEDINESS,
-            },
-        );
-        goods_result.insert(
-            GoodKind::YEN,
-            GoodLabel {
-                good_kind: GoodKind::YEN,
-                quantity: yen,
-                exchange_rate_buy: 1. / DEFAULT_EUR_YEN_EXCHANGE_RATE,
-                exchange_rate_sell: 1. / (DEFAULT_EUR_YEN_EXCHANGE_RATE * MARKET_GREEDINESS),
-            },
-        );
-        goods_result.insert(
-            GoodKind::USD,
-            GoodLabel {
-                good_kind: GoodKind::USD,
-                quantity: usd,
-                exchange_rate_buy: 1. / DEFAULT_EUR_USD_EXCHANGE_RATE,
-                exchange_rate_sell: 1. / (DEFAULT_EUR_USD_EXCHANGE_RATE * MARKET_GREEDINESS),
-            },
-        );
-        goods_result.insert(
-            GoodKind::YUAN,
-            GoodLabel {
-                good_kind: GoodKind::YUAN,
-                quantity: yuan,
-                exchange_rate_buy: 1. / DEFAULT_EUR_YUAN_EXCHANGE_RATE,
-                exchange_rate_sell: 1. / (DEFAULT_EUR_YUAN_EXCHANGE_RATE * MARKET_GREEDINESS),
-            },
-        );
+    /// Releases a sell lock before it expires: the locked default good goes back to the market right away.
+    ///
+    /// Settling a cancelled token with `sell` fails with `SellError::ExpiredToken`.
+    ///
+    /// Like `cancel_lock_buy`, only `FskNotifiable` subscribers are told.
+    pub fn cancel_lock_sell(&mut self, token: String) -> Result<(), CancelLockError> {
+        let contract = match self
+            .sell_contracts_archive
+            .cancel_contract(&token, self.time)
+        {
+            Ok(contract) => contract,
+            Err(err) => {
+                self.write_log(LogRecord::token_op(
+                    LogOp::CancelLockSell,
+                    &token,
+                    Some(&err),
+                ));
+                return Err(err);
+            }
+        };
 
-        let new_market = Rc::new(RefCell::new(FskMarket {
-            goods: goods_result,
-            buy_contracts_archive: ContractsArchive::new(),
-            sell_contracts_archive: ContractsArchive::new(),
-            subs: vec![],
-            log_output: FskMarket::initialize_log_file("FSK".to_string()),
-            time: 0,
-            last_trader_interaction: 0,
-        }));
-        //log market init
-        new_market.borrow().write_log_market_init();
-        //take the first snapshot
-        //new_market.borrow().take_snapshot(String::new());
-        //return it
-        new_market
-    }
+        //put the locked default good back in the market
+        self.goods.get_mut(&DEFAULT_GOOD_KIND).unwrap().quantity += contract.price;
 
-    fn new_file(path: &str) -> Rc<RefCell<dyn Market>>
-    where
-        Self: Sized,
-    {
-        let file_pat = path;
-        let exists = Path::new(file_pat).exists();
-        if !exists {
-            return FskMarket::new_random();
-        }
+        //log
+        self.write_log(LogRecord::token_op(LogOp::CancelLockSell, &token, None));
 
-        let file_open_res = OpenOptions::new().read(true).open(path);
-        //check if file open threw error
-        if let Ok(mut file) = file_open_res {
-            let mut market_json = String::new();
-            //if not try to read its content
-            if let Ok(_) = file.read_to_string(&mut market_json) {
-                //if read is succesfull try to parse the content into a MarketSnapshot
-                let market_parse_res: Result<MarketSnapshot, _> =
-                    serde_json::from_str(&market_json[..]);
-                if let Ok(market) = market_parse_res {
-                    //if parse is succesfull create a new FskMarket
-                    let new_market = Rc::new(RefCell::new(FskMarket {
-                        goods: market.goods,
-                        buy_contracts_archive: ContractsArchive::new(),
-                        sell_contracts_archive: ContractsArchive::new(),
-                        subs: vec![],
-                        log_output: FskMarket::initialize_log_file("FSK".to_string()),
-                        time: market.time,
-                        last_trader_interaction: market.last_trader_interaction,
-                    }));
-                    //log market init
-                    new_market.borrow().write_log_market_init();
-                    //take the first snapshot
-                    new_market.borrow().take_snapshot(String::new());
-                } else if let Err(err) = market_parse_res {
-                    println!(
-                        "Couldn't parse the market snapshot, check error below:\n{:?}",
-                        err
-                    );
-                }
-            }
-        } else if let Err(err) = file_open_res {
-            println!("Couldn't open snapshot file, check error below:\n{:?}", err);
-        }
-        //in case of error just return a random market
-        return FskMarket::new_random();
-    }
+        //save this interaction
+        self.last_trader_interaction = self.time;
 
-    fn get_name(&self) -> &'static str {
-        "FSK"
+        self.notify_fsk(FskEvent::LockSellCancelled {
+            token,
+            good_kind: contract.kind,
+            quantity: contract.quantity.to_f32(),
+            price: contract.price.to_f32(),
+        });
+        self.finish_operation("cancel_lock_sell");
+        Ok(())
     }
 
-    fn get_budget(&self) -> f32 {
+    /// Quantity of default good the market owns.
+    fn eur_reserve(&self) -> Fixed {
         self.goods.get(&DEFAULT_GOOD_KIND).unwrap().quantity
     }
 
-    fn get_buy_price(&self, kind: GoodKind, quantity: f32) -> Result<f32, MarketGetterError> {
-        let mut good_quantity = 0.;
+    fn market_greediness(&self) -> Fixed {
+        Fixed::from_f32(self.config.market_greediness)
+    }
+
+    /// `Market::get_buy_price` before it's rounded to `f32`.
+    fn buy_price(&self, kind: GoodKind, quantity: Fixed) -> Result<Fixed, MarketGetterError> {
+        let mut good_quantity = Fixed::ZERO;
 
         //the quantity is not positive
-        if quantity < 0. {
+        if quantity < Fixed::ZERO {
             return Err(MarketGetterError::NonPositiveQuantityAsked);
         }
 
@@ -607,375 +1061,747 @@ impl Market for FskMarket {
             good_quantity = good.quantity;
             if good.quantity > quantity {
                 //the market has enough quantity
-                return Ok(FskMarket::get_new_exchange_rate_buy(
-                    good.exchange_rate_buy,
-                    good_quantity,
-                    quantity,
-                ) * quantity);
+                return Ok(self.pricing.buy_price(good, self.eur_reserve(), quantity));
             }
         }
         //either goodkind was not found in self.goods or its quantity was not enough
         Err(MarketGetterError::InsufficientGoodQuantityAvailable {
             requested_good_kind: kind,
-            requested_good_quantity: quantity,
-            available_good_quantity: good_quantity,
+            requested_good_quantity: quantity.to_f32(),
+            available_good_quantity: good_quantity.to_f32(),
         })
     }
 
-    fn get_sell_price(&self, kind: GoodKind, quantity: f32) -> Result<f32, MarketGetterError> {
+    /// `Market::get_sell_price` before it's rounded to `f32`.
+    fn sell_price(&self, kind: GoodKind, quantity: Fixed) -> Result<Fixed, MarketGetterError> {
         //the quantity is not positive
-        if quantity <= 0. {
+        if quantity <= Fixed::ZERO {
             return Err(MarketGetterError::NonPositiveQuantityAsked);
         }
 
-        let maximum_price = quantity * self.goods.get(&kind).unwrap().exchange_rate_sell;
         //how much money the market pay (at max) for the good
+        let maximum_price = self.pricing.sell_price(
+            self.goods.get(&kind).unwrap(),
+            self.eur_reserve(),
+            quantity,
+            self.market_greediness(),
+        );
 
-        let available_default_good = self.get_budget();
-
-        Ok(maximum_price.min(available_default_good))
+        Ok(maximum_price.min(self.eur_reserve()))
     }
 
-    fn get_goods(&self) -> Vec<GoodLabel> {
-        let mut res = Vec::new();
-        for (gk, good_label) in &self.goods {
-            let mut new_good_label = good_label.clone();
-            if *gk != DEFAULT_GOOD_KIND {
-                //price impact calculation
-                new_good_label.exchange_rate_buy = FskMarket::get_new_exchange_rate_buy(
-                    good_label.exchange_rate_buy,
-                    good_label.quantity,
-                    1.,
-                );
+    fn update_price(&mut self, gk: &GoodKind, qty: Fixed) {
+        let eur_reserve = self.eur_reserve();
+        let market_greediness = self.market_greediness();
+        //calculate new exchange rates given the quantity traded
+        self.pricing.update_rates(
+            self.goods.get_mut(gk).unwrap(),
+            eur_reserve,
+            qty,
+            market_greediness,
+        );
+        //the trade may have moved the rates of the other goods as well
+        for (other_gk, reserve) in &mut self.goods {
+            if other_gk != gk {
+                self.pricing
+                    .refresh_rates(reserve, eur_reserve, market_greediness);
             }
-            res.push(new_good_label);
         }
-        res
     }
 
-    fn lock_buy(
+    /// Like `lock_buy`, but if the market can't supply the whole quantity at the bid it locks the
+    /// largest quantity it can, paying the bid pro rata.
+    ///
+    /// The resulting lock is a normal one: it's settled with `buy` using the returned token and price.
+    pub fn lock_buy_partial(
         &mut self,
         kind_to_buy: GoodKind,
         quantity_to_buy: f32,
         bid: f32,
         trader_name: String,
-    ) -> Result<String, LockBuyError> {
+    ) -> Result<PartialLock, LockError<LockBuyError>> {
+        if quantity_to_buy <= 0. {
+            let err = LockBuyError::NonPositiveQuantityToBuy {
+                negative_quantity_to_buy: quantity_to_buy,
+            };
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Market(err));
+        }
+        if bid <= 0. {
+            let err = LockBuyError::NonPositiveBid { negative_bid: bid };
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Market(err));
+        }
+        let available_quantity = self.goods.get(&kind_to_buy).unwrap().quantity;
+        if available_quantity <= Fixed::ZERO {
+            let err = LockBuyError::InsufficientGoodQuantityAvailable {
+                requested_good_kind: kind_to_buy,
+                requested_good_quantity: quantity_to_buy,
+                available_good_quantity: available_quantity.to_f32(),
+            };
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Market(err));
+        }
+
+        //the trader pays the same price per unit whatever quantity gets locked
+        let requested_quantity = Fixed::from_f32(quantity_to_buy);
+        let full_bid = Fixed::from_f32(bid);
+        let bid_per_unit = full_bid / requested_quantity;
+        let quantity = FskMarket::largest_fill(
+            requested_quantity.min(available_quantity),
+            |qty| matches!(self.buy_price(kind_to_buy, qty), Ok(price) if price <= bid_per_unit * qty),
+        );
+        if quantity <= Fixed::ZERO {
+            //more than the stock can only be locked in part, the market starts filling the request
+            //once the bid pays per unit what a small first quantity costs
+            let first = Fixed::ONE.min(available_quantity / Fixed::from_int(2));
+            let lowest_acceptable_bid = match self.buy_price(kind_to_buy, requested_quantity) {
+                Ok(price) => price.to_f32(),
+                Err(_) if first > Fixed::ZERO => self
+                    .buy_price(kind_to_buy, first)
+                    .map_or(f32::MAX, |price| {
+                        (price / first * requested_quantity).to_f32()
+                    }),
+                Err(_) => f32::MAX,
+            };
+            let err = LockBuyError::BidTooLow {
+                requested_good_kind: kind_to_buy,
+                requested_good_quantity: quantity_to_buy,
+                low_bid: bid,
+                lowest_acceptable_bid,
+            };
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Market(err));
+        }
+        let price = if quantity == requested_quantity {
+            full_bid
+        } else {
+            bid_per_unit * quantity
+        };
+
+        if let Err(err) = self.check_lock_quota(&trader_name, kind_to_buy, quantity, price) {
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Quota(err));
+        }
+
+        let token = self.add_buy_lock(kind_to_buy, quantity, price, trader_name);
+        Ok(PartialLock {
+            token,
+            quantity: quantity.to_f32(),
+            price: price.to_f32(),
+        })
+    }
+
+    /// Like `lock_sell`, but if the market can't take the whole quantity at the offer it locks the
+    /// largest quantity it can, paying the offer pro rata.
+    ///
+    /// The resulting lock is a normal one: it's settled with `sell` using the returned token and quantity.
+    pub fn lock_sell_partial(
+        &mut self,
+        kind_to_sell: GoodKind,
+        quantity_to_sell: f32,
+        offer: f32,
+        trader_name: String,
+    ) -> Result<PartialLock, LockError<LockSellError>> {
+        if quantity_to_sell <= 0. {
+            let err = LockSellError::NonPositiveQuantityToSell {
+                negative_quantity_to_sell: quantity_to_sell,
+            };
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Market(err));
+        }
+        if offer < 0. {
+            let err = LockSellError::NonPositiveOffer {
+                negative_offer: offer,
+            };
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Market(err));
+        }
+        let budget = self.eur_reserve();
+        if budget <= Fixed::ZERO && offer > 0. {
+            let err = LockSellError::InsufficientDefaultGoodQuantityAvailable {
+                offered_good_kind: kind_to_sell,
+                offered_good_quantity: quantity_to_sell,
+                available_good_quantity: budget.to_f32(),
+            };
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Market(err));
+        }
+
+        //the market pays the same price per unit whatever quantity gets locked
+        let requested_quantity = Fixed::from_f32(quantity_to_sell);
+        let full_offer = Fixed::from_f32(offer);
+        let offer_per_unit = full_offer / requested_quantity;
+        let affordable_quantity = if offer_per_unit > Fixed::ZERO {
+            requested_quantity.min(budget / offer_per_unit)
+        } else {
+            requested_quantity
+        };
+        let quantity = FskMarket::largest_fill(
+            affordable_quantity,
+            |qty| matches!(self.sell_price(kind_to_sell, qty), Ok(price) if price >= offer_per_unit * qty),
+        );
+        if quantity <= Fixed::ZERO {
+            let err = LockSellError::OfferTooHigh {
+                offered_good_kind: kind_to_sell,
+                offered_good_quantity: quantity_to_sell,
+                high_offer: offer,
+                highest_acceptable_offer: self
+                    .get_sell_price(kind_to_sell, quantity_to_sell)
+                    .unwrap_or(0.),
+            };
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Market(err));
+        }
+        //rounding must never lock more than the budget
+        let price = if quantity == requested_quantity {
+            full_offer.min(budget)
+        } else {
+            (offer_per_unit * quantity).min(budget)
+        };
+
+        if let Err(err) = self.check_lock_quota(&trader_name, kind_to_sell, quantity, price) {
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Quota(err));
+        }
+
+        let token = self.add_sell_lock(kind_to_sell, quantity, price, trader_name);
+        Ok(PartialLock {
+            token,
+            quantity: quantity.to_f32(),
+            price: price.to_f32(),
+        })
+    }
+
+    /// Like `Market::lock_buy`, but quota errors are reported as they are instead of being mapped
+    /// onto the closest `LockBuyError` variant.
+    pub fn try_lock_buy(
+        &mut self,
+        kind_to_buy: GoodKind,
+        quantity_to_buy: f32,
+        bid: f32,
+        trader_name: String,
+    ) -> Result<String, LockError<LockBuyError>> {
         //1
         if quantity_to_buy < 0. {
-            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid);
-            return Err(LockBuyError::NonPositiveQuantityToBuy {
+            let err = LockBuyError::NonPositiveQuantityToBuy {
                 negative_quantity_to_buy: quantity_to_buy,
-            });
+            };
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Market(err));
         }
 
         //2
         if bid < 0. {
-            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid);
-            return Err(LockBuyError::NonPositiveBid { negative_bid: bid });
+            let err = LockBuyError::NonPositiveBid { negative_bid: bid };
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Market(err));
         }
+        let quantity = Fixed::from_f32(quantity_to_buy);
+        let price = Fixed::from_f32(bid);
         //get immutable reference so there are no borrow errors
         let good = self.goods.get(&kind_to_buy).unwrap(); //assume that goods always contains every goodkind
 
         //5
-        if good.quantity < quantity_to_buy {
-            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid);
-            return Err(LockBuyError::InsufficientGoodQuantityAvailable {
+        if good.quantity < quantity {
+            let err = LockBuyError::InsufficientGoodQuantityAvailable {
                 requested_good_kind: kind_to_buy,
                 requested_good_quantity: quantity_to_buy,
-                available_good_quantity: good.quantity,
-            });
+                available_good_quantity: good.quantity.to_f32(),
+            };
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Market(err));
         }
 
         //unwrap won't panic
-        let get_buy_price_result = self
-            .get_buy_price(kind_to_buy.clone(), quantity_to_buy)
-            .unwrap();
+        let get_buy_price_result = self.get_buy_price(kind_to_buy, quantity_to_buy).unwrap();
 
         //6
+        //compared as the trader was quoted, a bid of exactly get_buy_price is accepted
         if bid < get_buy_price_result {
-            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid);
-            return Err(LockBuyError::BidTooLow {
-                requested_good_kind: kind_to_buy.clone(),
+            let err = LockBuyError::BidTooLow {
+                requested_good_kind: kind_to_buy,
                 requested_good_quantity: quantity_to_buy,
                 low_bid: bid,
                 lowest_acceptable_bid: get_buy_price_result,
-            });
+            };
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Market(err));
         }
 
-        //this time we need the mutable reference
-        let mut good = self.goods.get_mut(&kind_to_buy).unwrap(); //assume that goods always contains every goodkind
-        good.quantity -= quantity_to_buy;
-
-        //create the token
-        let token = self.buy_contracts_archive.new_token();
-
-        //register (via the market-local Good Metadata) the fact that quantity quantity_to_buy of good kind_to_buy is to be bought for price bid.
-        self.buy_contracts_archive
-            .add_contract(&Rc::new(LockContract {
-                token: token.to_string(),
-                good: Good::new(kind_to_buy.clone(), quantity_to_buy),
-                price: bid,
-                expiry_time: self.time + LOCK_INITIAL_TTL,
-            }));
-        //log
-        self.write_log_buy_ok(trader_name, kind_to_buy, quantity_to_buy, bid, &token);
-
-        //save this interaction
-        self.last_trader_interaction = self.time;
-
-        //notify all the markets of the lock buy
-        self.notify(Event {
-            kind: EventKind::LockedBuy,
-            good_kind: kind_to_buy.clone(),
-            quantity: quantity_to_buy,
-            price: bid,
-        });
+        if let Err(err) = self.check_lock_quota(&trader_name, kind_to_buy, quantity, price) {
+            self.write_log_lock_buy_error(trader_name, kind_to_buy, quantity_to_buy, bid, &err);
+            return Err(LockError::Quota(err));
+        }
 
-        return Ok(token.to_string());
+        let token = self.add_buy_lock(kind_to_buy, quantity, price, trader_name);
+        Ok(token)
     }
 
-    fn buy(&mut self, token: String, cash: &mut Good) -> Result<Good, BuyError> {
-        //check if the token is valid or expired or unrecognized
-        let op_contract = self.buy_contracts_archive.contracts_by_token.get(&token);
-
+    /// Like `Market::lock_sell`, but quota errors are reported as they are instead of being mapped
+    /// onto the closest `LockSellError` variant.
+    pub fn try_lock_sell(
+        &mut self,
+        kind_to_sell: GoodKind,
+        quantity_to_sell: f32,
+        offer: f32,
+        trader_name: String,
+    ) -> Result<String, LockError<LockSellError>> {
         //1
-        if op_contract.is_none() {
-            self.write_log_buy_error(&token);
-            if self
-                .buy_contracts_archive
-                .expired_contracts
-                .contains(&token)
-            {
-                return Err(BuyError::ExpiredToken {
-                    expired_token: token,
-                });
-            }
-
-            return Err(BuyError::UnrecognizedToken {
-                unrecognized_token: token,
-            });
+        if quantity_to_sell <= 0. {
+            let err = LockSellError::NonPositiveQuantityToSell {
+                negative_quantity_to_sell: quantity_to_sell,
+            };
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Market(err));
         }
 
-        let contract = op_contract.unwrap();
-        let contract_price = contract.price;
-
         //2
-        if contract.expiry_time <= self.time {
-            self.write_log_buy_error(&token);
-            return Err(BuyError::ExpiredToken {
-                expired_token: token,
-            });
+        if offer < 0. {
+            let err = LockSellError::NonPositiveOffer {
+                negative_offer: offer,
+            };
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Market(err));
         }
 
-        //3
-        if cash.get_kind() != DEFAULT_GOOD_KIND {
-            self.write_log_buy_error(&token);
-            return Err(BuyError::GoodKindNotDefault {
-                non_default_good_kind: cash.get_kind(),
-            });
+        //5
+        if self.get_budget() < offer {
+            let err = LockSellError::InsufficientDefaultGoodQuantityAvailable {
+                offered_good_kind: kind_to_sell,
+                offered_good_quantity: quantity_to_sell,
+                available_good_quantity: self.get_budget(),
+            };
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Market(err));
         }
 
-        //4
-        if cash.get_qty() < contract_price {
-            self.write_log_buy_error(&token);
-            return Err(BuyError::InsufficientGoodQuantity {
-                contained_quantity: cash.get_qty(),
-                pre_agreed_quantity: contract_price,
-            });
+        //6
+        //compared as the trader was quoted, an offer of exactly get_sell_price is accepted
+        let highest_acceptable_offer = self
+            .get_sell_price(kind_to_sell, quantity_to_sell)
+            .unwrap_or(0.);
+        if highest_acceptable_offer < offer {
+            let err = LockSellError::OfferTooHigh {
+                offered_good_kind: kind_to_sell,
+                offered_good_quantity: quantity_to_sell,
+                high_offer: offer,
+                highest_acceptable_offer,
+            };
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Market(err));
         }
 
-        //everything checks out, the buy can proceed
-        //removing the pre-agreed quantity from cash
-        cash.split(contract_price);
+        let quantity = Fixed::from_f32(quantity_to_sell);
+        //an offer of the whole budget must not lock more than the budget once it's exact
+        let price = Fixed::from_f32(offer).min(self.eur_reserve());
+        if let Err(err) = self.check_lock_quota(&trader_name, kind_to_sell, quantity, price) {
+            self.write_log_lock_sell_error(
+                trader_name,
+                kind_to_sell,
+                quantity_to_sell,
+                offer,
+                &err,
+            );
+            return Err(LockError::Quota(err));
+        }
 
-        //put the pre-agreed quantity in the market
-        self.goods.get_mut(&DEFAULT_GOOD_KIND).unwrap().quantity += contract_price;
+        let token = self.add_sell_lock(kind_to_sell, quantity, price, trader_name);
+        Ok(token)
+    }
 
-        let good_to_return = Good::new(contract.good.get_kind(), contract.good.get_qty());
+    /// Largest quantity in `(0, upper]` accepted by `accepts`, or 0 if there is none.
+    ///
+    /// `accepts` must be monotonic: if it accepts a quantity it accepts every smaller one.
+    fn largest_fill<F: Fn(Fixed) -> bool>(upper: Fixed, accepts: F) -> Fixed {
+        if upper <= Fixed::ZERO || accepts(upper) {
+            return upper.max(Fixed::ZERO);
+        }
+        let mut low = Fixed::ZERO;
+        let mut high = upper;
+        for _ in 0..128 {
+            let mid = (low + high) / Fixed::from_int(2);
+            if mid <= low || mid >= high {
+                break;
+            }
+            if accepts(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
 
-        //update the price of all de goods according to the rules in the Market prices fluctuation section
-        //new exchange rates of the traded good
-        let gk = &contract.good.get_kind();
-        self.update_price(gk, contract.good.get_qty());
+    /// Extends a buy lock by `lock_initial_ttl` ticks, at most `max_lock_renewals` times.
+    ///
+    /// Every extension raises the price to pay by `lock_renewal_fee` times the current price.
+    pub fn extend_lock_buy(&mut self, token: String) -> Result<ExtendedLock, ExtendLockError> {
+        let time = self.time;
+        let fee_rate = Fixed::from_f32(self.config.lock_renewal_fee);
+        let contract = match self.buy_contracts_archive.extend_contract(
+            &token,
+            time,
+            self.config.lock_initial_ttl,
+            self.config.max_lock_renewals,
+        ) {
+            Ok(contract) => contract,
+            Err(err) => {
+                self.write_log(LogRecord::token_op(
+                    LogOp::ExtendLockBuy,
+                    &token,
+                    Some(&err),
+                ));
+                return Err(err);
+            }
+        };
+        //the trader pays the fee at settlement
+        contract.price += contract.price * fee_rate;
+        let extended_lock = ExtendedLock {
+            expires_in: contract.expiry_time - time,
+            price: contract.price.to_f32(),
+            renewals: contract.renewals,
+        };
 
         //log
-        self.write_log_entry(format!("BUY-TOKEN:{}-OK", token));
-
-        //remove the corresponding contract
-        self.buy_contracts_archive.consume_contract(&token);
+        self.write_log(LogRecord::token_op(LogOp::ExtendLockBuy, &token, None));
 
         //save this interaction
         self.last_trader_interaction = self.time;
+        self.finish_operation("extend_lock_buy");
+        Ok(extended_lock)
+    }
 
-        //notify all the markets of the transaction
-        self.notify(Event {
-            kind: (EventKind::Bought),
-            good_kind: good_to_return.get_kind(),
-            quantity: good_to_return.get_qty(),
-            price: contract_price,
-        });
+    /// Extends a sell lock by `lock_initial_ttl` ticks, at most `max_lock_renewals` times.
+    ///
+    /// Every extension lowers the price the market pays by `lock_renewal_fee` times the current price.
+    pub fn extend_lock_sell(&mut self, token: String) -> Result<ExtendedLock, ExtendLockError> {
+        let time = self.time;
+        let fee_rate = Fixed::from_f32(self.config.lock_renewal_fee);
+        let contract = match self.sell_contracts_archive.extend_contract(
+            &token,
+            time,
+            self.config.lock_initial_ttl,
+            self.config.max_lock_renewals,
+        ) {
+            Ok(contract) => contract,
+            Err(err) => {
+                self.write_log(LogRecord::token_op(
+                    LogOp::ExtendLockSell,
+                    &token,
+                    Some(&err),
+                ));
+                return Err(err);
+            }
+        };
+        //the fee is kept out of the default good locked for the trader
+        let fee = contract.price * fee_rate;
+        contract.price -= fee;
+        let extended_lock = ExtendedLock {
+            expires_in: contract.expiry_time - time,
+            price: contract.price.to_f32(),
+            renewals: contract.renewals,
+        };
+        self.goods.get_mut(&DEFAULT_GOOD_KIND).unwrap().quantity += fee;
 
-        Ok(good_to_return)
+        //log
+        self.write_log(LogRecord::token_op(LogOp::ExtendLockSell, &token, None));
+
+        //save this interaction
+        self.last_trader_interaction = self.time;
+        self.finish_operation("extend_lock_sell");
+        Ok(extended_lock)
     }
 
-    fn lock_sell(
+    /// Like `Market::buy`, but fails with `SettleError::WrongTrader` unless `token` was locked by
+    /// `trader_name`. The only way to settle a buy lock in `TokenMode::TraderBound`.
+    pub fn buy_as(
         &mut self,
-        kind_to_sell: GoodKind,
-        quantity_to_sell: f32,
-        offer: f32,
         trader_name: String,
-    ) -> Result<String, LockSellError> {
+        token: String,
+        cash: &mut Good,
+    ) -> Result<Good, SettleError<BuyError>> {
+        if let Some(contract) = self.buy_contracts_archive.contracts_by_token.get(&token) {
+            if contract.trader_name != trader_name {
+                let err = SettleError::WrongTrader {
+                    token: token.clone(),
+                    trader_name,
+                };
+                self.write_log_buy_error(&token, &err);
+                return Err(err);
+            }
+        }
+        self.settle_buy(token, cash).map_err(SettleError::Market)
+    }
+
+    /// Like `Market::sell`, but fails with `SettleError::WrongTrader` unless `token` was locked by
+    /// `trader_name`. The only way to settle a sell lock in `TokenMode::TraderBound`.
+    pub fn sell_as(
+        &mut self,
+        trader_name: String,
+        token: String,
+        good: &mut Good,
+    ) -> Result<Good, SettleError<SellError>> {
+        if let Some(contract) = self.sell_contracts_archive.contracts_by_token.get(&token) {
+            if contract.trader_name != trader_name {
+                let err = SettleError::WrongTrader {
+                    token: token.clone(),
+                    trader_name,
+                };
+                self.write_log_sell_error(&token, &err);
+                return Err(err);
+            }
+        }
+        self.settle_sell(token, good).map_err(SettleError::Market)
+    }
+
+    fn settle_buy(&mut self, token: String, cash: &mut Good) -> Result<Good, BuyError> {
+        //check if the token is valid or expired or unrecognized
+        let op_contract = self.buy_contracts_archive.contracts_by_token.get(&token);
+
         //1
-        if quantity_to_sell <= 0. {
-            self.write_log_lock_sell_error(trader_name, kind_to_sell, quantity_to_sell, offer);
-            return Err(LockSellError::NonPositiveQuantityToSell {
-                negative_quantity_to_sell: quantity_to_sell,
-            });
+        if op_contract.is_none() {
+            //BuyError has no variant for cancelled locks, they are released just like expired ones
+            let err = if self
+                .buy_contracts_archive
+                .expired_contracts
+                .contains_key(&token)
+                || self
+                    .buy_contracts_archive
+                    .cancelled_contracts
+                    .contains_key(&token)
+            {
+                BuyError::ExpiredToken {
+                    expired_token: token.clone(),
+                }
+            } else {
+                BuyError::UnrecognizedToken {
+                    unrecognized_token: token.clone(),
+                }
+            };
+            self.write_log_buy_error(&token, &err);
+            return Err(err);
         }
 
+        let contract = op_contract.unwrap();
+        let contract_price = contract.price;
+
         //2
-        if offer < 0. {
-            self.write_log_lock_sell_error(trader_name, kind_to_sell, quantity_to_sell, offer);
-            return Err(LockSellError::NonPositiveOffer {
-                negative_offer: offer,
-            });
+        if contract.expiry_time <= self.time {
+            let err = BuyError::ExpiredToken {
+                expired_token: token.clone(),
+            };
+            self.write_log_buy_error(&token, &err);
+            return Err(err);
         }
 
-        //5
-        if self.get_budget() < offer {
-            self.write_log_lock_sell_error(trader_name, kind_to_sell, quantity_to_sell, offer);
-            return Err(LockSellError::InsufficientDefaultGoodQuantityAvailable {
-                offered_good_kind: kind_to_sell,
-                offered_good_quantity: quantity_to_sell,
-                available_good_quantity: self.get_budget(),
-            });
+        //3
+        if cash.get_kind() != DEFAULT_GOOD_KIND {
+            let err = BuyError::GoodKindNotDefault {
+                non_default_good_kind: cash.get_kind(),
+            };
+            self.write_log_buy_error(&token, &err);
+            return Err(err);
         }
 
-        //6
-        let highest_acceptable_offer = self
-            .get_sell_price(kind_to_sell, quantity_to_sell)
-            .unwrap_or(0.);
-        if highest_acceptable_offer < offer {
-            self.write_log_lock_sell_error(trader_name, kind_to_sell, quantity_to_sell, offer);
-            return Err(LockSellError::OfferTooHigh {
-                offered_good_kind: kind_to_sell,
-                offered_good_quantity: quantity_to_sell,
-                high_offer: offer,
-                highest_acceptable_offer,
-            });
+        //4
+        //the trader can only pay what the price rounds to
+        if cash.get_qty() < contract_price.to_f32() {
+            let err = BuyError::InsufficientGoodQuantity {
+                contained_quantity: cash.get_qty(),
+                pre_agreed_quantity: contract_price.to_f32(),
+            };
+            self.write_log_buy_error(&token, &err);
+            return Err(err);
         }
 
-        //we chose to decrease the budget when goods are locked, to avoid having to keep track of locked default good. In case the lock expires, default currency will be put back in goods.
-        self.goods.get_mut(&DEFAULT_GOOD_KIND).unwrap().quantity -= offer;
+        //everything checks out, the buy can proceed
+        //removing the pre-agreed quantity from cash
+        let _ = cash.split(contract_price.to_f32());
 
-        //create token
-        let token = self.sell_contracts_archive.new_token();
+        //put the pre-agreed quantity in the market
+        self.goods.get_mut(&DEFAULT_GOOD_KIND).unwrap().quantity += contract_price;
 
-        //add the contract
-        self.sell_contracts_archive
-            .add_contract(&Rc::new(LockContract {
-                token: token.clone(),
-                good: Good::new(kind_to_sell, quantity_to_sell),
-                price: offer,
-                expiry_time: self.time + LOCK_INITIAL_TTL,
-            }));
+        let good_to_return = Good::new(contract.kind, contract.quantity.to_f32());
+
+        //update the price of all de goods according to the rules in the Market prices fluctuation section
+        //new exchange rates of the traded good
+        let (gk, quantity) = (contract.kind, contract.quantity);
+        self.update_price(&gk, quantity);
 
         //log
-        self.write_log_sell_ok(trader_name, kind_to_sell, quantity_to_sell, offer, &token);
+        self.write_log(LogRecord::token_op(LogOp::Buy, &token, None));
+
+        //remove the corresponding contract
+        self.buy_contracts_archive
+            .consume_contract(&token, self.time);
+        //the trader's cash entered the market and the locked good left it
+        self.record_flow(DEFAULT_GOOD_KIND, contract_price);
+        self.record_flow(gk, -quantity);
 
         //save this interaction
         self.last_trader_interaction = self.time;
 
-        //notify all the markets of the lock sell
+        //notify all the markets of the transaction
+        self.trade_settled = true;
         self.notify(Event {
-            kind: EventKind::LockedSell,
-            good_kind: kind_to_sell,
-            quantity: quantity_to_sell,
-            price: offer,
+            kind: (EventKind::Bought),
+            good_kind: good_to_return.get_kind(),
+            quantity: good_to_return.get_qty(),
+            price: contract_price.to_f32(),
         });
 
-        Ok(token)
+        self.finish_operation("buy");
+        Ok(good_to_return)
     }
 
-    fn sell(&mut self, token: String, good: &mut Good) -> Result<Good, SellError> {
+    fn settle_sell(&mut self, token: String, good: &mut Good) -> Result<Good, SellError> {
         let op_contract = self.sell_contracts_archive.contracts_by_token.get(&token);
 
         //1
         if op_contract.is_none() {
-            self.write_log_sell_error(&token);
-
-            if self
+            //SellError has no variant for cancelled locks, they are released just like expired ones
+            let err = if self
                 .sell_contracts_archive
                 .expired_contracts
-                .contains(&token)
+                .contains_key(&token)
+                || self
+                    .sell_contracts_archive
+                    .cancelled_contracts
+                    .contains_key(&token)
             {
-                return Err(SellError::ExpiredToken {
-                    expired_token: token,
-                });
-            }
-
-            return Err(SellError::UnrecognizedToken {
-                unrecognized_token: token,
-            });
+                SellError::ExpiredToken {
+                    expired_token: token.clone(),
+                }
+            } else {
+                SellError::UnrecognizedToken {
+                    unrecognized_token: token.clone(),
+                }
+            };
+            self.write_log_sell_error(&token, &err);
+            return Err(err);
         }
 
         let contract = op_contract.unwrap();
 
         //2
         if contract.expiry_time <= self.time {
-            self.write_log_sell_error(&token);
-            return Err(SellError::ExpiredToken {
-                expired_token: token,
-            });
+            let err = SellError::ExpiredToken {
+                expired_token: token.clone(),
+            };
+            self.write_log_sell_error(&token, &err);
+            return Err(err);
         }
 
         //3
-        if contract.good.get_kind() != good.get_kind() {
-            self.write_log_sell_error(&token);
-            return Err(SellError::WrongGoodKind {
+        if contract.kind != good.get_kind() {
+            let err = SellError::WrongGoodKind {
                 wrong_good_kind: good.get_kind(),
-                pre_agreed_kind: contract.good.get_kind(),
-            });
+                pre_agreed_kind: contract.kind,
+            };
+            self.write_log_sell_error(&token, &err);
+            return Err(err);
         }
 
         //4
-        if good.get_qty() < contract.good.get_qty() {
-            self.write_log_sell_error(&token);
-
-            return Err(SellError::InsufficientGoodQuantity {
+        //the trader can only hand over what the quantity rounds to
+        if good.get_qty() < contract.quantity.to_f32() {
+            let err = SellError::InsufficientGoodQuantity {
                 contained_quantity: good.get_qty(),
-                pre_agreed_quantity: contract.good.get_qty(),
-            });
+                pre_agreed_quantity: contract.quantity.to_f32(),
+            };
+            self.write_log_sell_error(&token, &err);
+            return Err(err);
         }
 
         //everything checks out, the sell can proceed
 
         //this is the default currency that is going to be returned to the seller (the trader)
-        let good_to_return = Good::new(DEFAULT_GOOD_KIND, contract.price); //don't need to decrease owned good, already did that in lock_sell
+        let good_to_return = Good::new(DEFAULT_GOOD_KIND, contract.price.to_f32()); //don't need to decrease owned good, already did that in lock_sell
 
         //add the good the trader gave us to the supply of the corresponding good in the market
         //assume goods contains every goodkind: unwrap is safe
-        self.goods.get_mut(&good.get_kind()).unwrap().quantity += contract.good.get_qty();
+        self.goods.get_mut(&good.get_kind()).unwrap().quantity += contract.quantity;
         //split the good the trader gave to the market - in case he sent more than previously decided by the lock
-        good.split(contract.good.get_qty());
+        let _ = good.split(contract.quantity.to_f32());
 
         //update the price of all de goods according to the rules in the Market prices fluctuation section
         //new exchange rates of the traded good
-        let gk = &contract.good.get_kind();
-        if *gk != GoodKind::EUR {
-            self.update_price(gk, -contract.good.get_qty());
+        //the pricing strategy settles on the same sell rate get_sell_price quoted for this quantity
+        let (gk, quantity, price) = (contract.kind, contract.quantity, contract.price);
+        if gk != GoodKind::EUR {
+            self.update_price(&gk, -quantity);
         }
 
         //log
-        self.write_log_entry(format!("SELL-TOKEN:{}-OK", token));
+        self.write_log(LogRecord::token_op(LogOp::Sell, &token, None));
 
         //remove the corresponding contract
-        self.sell_contracts_archive.consume_contract(&token);
+        self.sell_contracts_archive
+            .consume_contract(&token, self.time);
+        //the trader's good entered the market and the locked EUR left it
+        self.record_flow(gk, quantity);
+        self.record_flow(DEFAULT_GOOD_KIND, -price);
 
         //save this interaction
         self.last_trader_interaction = self.time;
 
         //notify all the markets of the transaction
+        self.trade_settled = true;
         self.notify(Event {
             kind: EventKind::Sold,
             good_kind: good.get_kind(),
@@ -983,6 +1809,927 @@ impl Market for FskMarket {
             price: good_to_return.get_qty(),
         });
 
+        self.finish_operation("sell");
         Ok(good_to_return)
     }
+
+    /// Quantity of every good held by open locks: the goods of buy locks and the EUR of sell locks.
+    fn locked_reserves(&self) -> HashMap<GoodKind, Fixed> {
+        let mut locked: HashMap<GoodKind, Fixed> =
+            self.goods.keys().map(|kind| (*kind, Fixed::ZERO)).collect();
+        for contract in self.buy_contracts_archive.contracts_by_token.values() {
+            *locked.entry(contract.kind).or_default() += contract.quantity;
+        }
+        for contract in self.sell_contracts_archive.contracts_by_token.values() {
+            *locked.entry(DEFAULT_GOOD_KIND).or_default() += contract.price;
+        }
+        locked
+    }
+
+    /// Inventory plus locked reserves of every good, the value the market is accountable for.
+    fn reserve_totals(&self) -> HashMap<GoodKind, Fixed> {
+        let mut totals = self.locked_reserves();
+        for (kind, reserve) in &self.goods {
+            *totals.entry(*kind).or_default() += reserve.quantity;
+        }
+        totals
+    }
+
+    /// Records `quantity` of `kind` entering the market through a settlement, negative if it left.
+    fn record_flow(&mut self, kind: GoodKind, quantity: Fixed) {
+        *self.expected_totals.entry(kind).or_default() += quantity;
+    }
+
+    /// Runs once `operation` changed the market: checks the invariants and rotates the log if it's
+    /// due, so every log file starts in between operations.
+    fn finish_operation(&self, operation: &str) {
+        self.check_invariants(operation);
+        self.rotate_log_if_due();
+    }
+
+    /// Panics with a dump of the market if `operation` created or destroyed value, or left a
+    /// negative quantity, rate or lock behind. Does nothing unless `check_invariants` is set in
+    /// the config.
+    ///
+    /// `Fixed` can't be NaN or infinite, the closest it gets is the `Fixed::MAX` rate pricing
+    /// strategies quote for a good that ran out.
+    fn check_invariants(&self, operation: &str) {
+        if !self.config.check_invariants {
+            return;
+        }
+        let mut violations = vec![];
+        let totals = self.reserve_totals();
+        for (kind, reserve) in &self.goods {
+            let fields = [
+                ("quantity", reserve.quantity),
+                ("exchange_rate_buy", reserve.exchange_rate_buy),
+                ("exchange_rate_sell", reserve.exchange_rate_sell),
+            ];
+            for (field, value) in fields {
+                if value < Fixed::ZERO {
+                    violations.push(format!("goods.{}.{} is {}", kind, field, value));
+                }
+            }
+            let expected = self.expected_totals.get(kind).copied().unwrap_or_default();
+            if totals[kind] != expected {
+                violations.push(format!(
+                    "{} inventory and locks add up to {}, settlements add up to {}",
+                    kind, totals[kind], expected
+                ));
+            }
+        }
+        let archives = [
+            ("buy", &self.buy_contracts_archive),
+            ("sell", &self.sell_contracts_archive),
+        ];
+        for (side, archive) in archives {
+            for contract in archive.contracts_by_token.values() {
+                if contract.quantity < Fixed::ZERO || contract.price < Fixed::ZERO {
+                    violations.push(format!(
+                        "{} lock {} holds {} {} for {} EUR",
+                        side, contract.token, contract.quantity, contract.kind, contract.price
+                    ));
+                }
+            }
+        }
+        if !violations.is_empty() {
+            panic!(
+                "{}: invariants broken after {} at tick {}\n{}\n{}",
+                self.get_name(),
+                operation,
+                self.time,
+                violations.join("\n"),
+                self.state_dump()
+            );
+        }
+    }
+
+    /// Every reserve and open lock, one per line, for `check_invariants`.
+    fn state_dump(&self) -> String {
+        let locked = self.locked_reserves();
+        let mut lines = vec![];
+        for kind in [GoodKind::EUR, GoodKind::YEN, GoodKind::USD, GoodKind::YUAN] {
+            if let Some(reserve) = self.goods.get(&kind) {
+                lines.push(format!(
+                    "{}: quantity {}, locked {}, expected total {}, buy rate {}, sell rate {}",
+                    kind,
+                    reserve.quantity,
+                    locked[&kind],
+                    self.expected_totals.get(&kind).copied().unwrap_or_default(),
+                    reserve.exchange_rate_buy,
+                    reserve.exchange_rate_sell
+                ));
+            }
+        }
+        let archives = [
+            ("buy", &self.buy_contracts_archive),
+            ("sell", &self.sell_contracts_archive),
+        ];
+        for (side, archive) in archives {
+            let mut contracts: Vec<&LockContract> = archive.contracts_by_token.values().collect();
+            contracts.sort_by(|a, b| a.token.cmp(&b.token));
+            for contract in contracts {
+                lines.push(format!(
+                    "{} lock {}: {} {} for {} EUR, trader {}, expires at tick {}",
+                    side,
+                    contract.token,
+                    contract.quantity,
+                    contract.kind,
+                    contract.price,
+                    contract.trader_name,
+                    contract.expiry_time
+                ));
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Takes `quantity` of `kind` out of the market and registers the buy lock for it.
+    fn add_buy_lock(
+        &mut self,
+        kind_to_buy: GoodKind,
+        quantity_to_buy: Fixed,
+        bid: Fixed,
+        trader_name: String,
+    ) -> String {
+        //assume that goods always contains every goodkind
+        self.goods.get_mut(&kind_to_buy).unwrap().quantity -= quantity_to_buy;
+
+        //create the token
+        let token = self
+            .buy_contracts_archive
+            .new_token(&mut self.rng, self.config.token_mode);
+
+        //register (via the market-local Good Metadata) the fact that quantity quantity_to_buy of good kind_to_buy is to be bought for price bid.
+        self.buy_contracts_archive.add_contract(LockContract {
+            token: token.clone(),
+            trader_name: trader_name.clone(),
+            kind: kind_to_buy,
+            quantity: quantity_to_buy,
+            price: bid,
+            expiry_time: self.time + self.config.lock_initial_ttl,
+            renewals: 0,
+        });
+        //log
+        self.write_log_buy_ok(
+            trader_name,
+            kind_to_buy,
+            quantity_to_buy.to_f32(),
+            bid.to_f32(),
+            &token,
+        );
+
+        //save this interaction
+        self.last_trader_interaction = self.time;
+
+        //notify all the markets of the lock buy
+        self.notify(Event {
+            kind: EventKind::LockedBuy,
+            good_kind: kind_to_buy,
+            quantity: quantity_to_buy.to_f32(),
+            price: bid.to_f32(),
+        });
+
+        self.finish_operation("lock_buy");
+        token
+    }
+
+    /// Takes `offer` out of the budget and registers the sell lock for it.
+    fn add_sell_lock(
+        &mut self,
+        kind_to_sell: GoodKind,
+        quantity_to_sell: Fixed,
+        offer: Fixed,
+        trader_name: String,
+    ) -> String {
+        //we chose to decrease the budget when goods are locked, to avoid having to keep track of locked default good. In case the lock expires, default currency will be put back in goods.
+        self.goods.get_mut(&DEFAULT_GOOD_KIND).unwrap().quantity -= offer;
+
+        //create token
+        let token = self
+            .sell_contracts_archive
+            .new_token(&mut self.rng, self.config.token_mode);
+
+        //add the contract
+        self.sell_contracts_archive.add_contract(LockContract {
+            token: token.clone(),
+            trader_name: trader_name.clone(),
+            kind: kind_to_sell,
+            quantity: quantity_to_sell,
+            price: offer,
+            expiry_time: self.time + self.config.lock_initial_ttl,
+            renewals: 0,
+        });
+
+        //log
+        self.write_log_sell_ok(
+            trader_name,
+            kind_to_sell,
+            quantity_to_sell.to_f32(),
+            offer.to_f32(),
+            &token,
+        );
+
+        //save this interaction
+        self.last_trader_interaction = self.time;
+
+        //notify all the markets of the lock sell
+        self.notify(Event {
+            kind: EventKind::LockedSell,
+            good_kind: kind_to_sell,
+            quantity: quantity_to_sell.to_f32(),
+            price: offer.to_f32(),
+        });
+
+        self.finish_operation("lock_sell");
+        token
+    }
+
+    /// Writes a snapshot named `filename` in the snapshot directory.
+    ///
+    /// An empty `filename` uses `snapshot_name_template`, only those snapshots are subject to the
+    /// retention rules of the config.
+    fn take_snapshot(&self, filename: String) {
+        if !filename.is_empty() {
+            self.write_snapshot(&filename);
+            return;
+        }
+        let filename = self.snapshot_file_name(&self.config.snapshot_name_template);
+        if self.write_snapshot(&filename) {
+            self.record_snapshot(self.config.snapshot_dir.join(filename));
+            *self.snapshot_rates.borrow_mut() = FskMarket::buy_rates(&self.goods);
+        }
+    }
+
+    fn buy_rates(goods: &HashMap<GoodKind, Reserve>) -> HashMap<GoodKind, Fixed> {
+        goods
+            .iter()
+            .map(|(kind, reserve)| (*kind, reserve.exchange_rate_buy))
+            .collect()
+    }
+
+    /// Whether the snapshot schedule of the config asks for a snapshot at the current tick.
+    fn snapshot_due(&self, traded: bool) -> bool {
+        if matches!(self.config.snapshot_every_ticks, Some(every) if self.time % every == 0) {
+            return true;
+        }
+        if traded && self.config.snapshot_on_trade {
+            return true;
+        }
+        if let Some(threshold) = self.config.snapshot_on_price_move {
+            let snapshot_rates = self.snapshot_rates.borrow();
+            //before the first snapshot the rates are compared to the ones the market started with
+            let threshold = Fixed::from_f32(threshold);
+            return self.goods.iter().any(|(kind, reserve)| {
+                matches!(snapshot_rates.get(kind), Some(old_rate) if *old_rate > Fixed::ZERO
+                    && ((reserve.exchange_rate_buy - *old_rate) / *old_rate).abs() > threshold)
+            });
+        }
+        false
+    }
+
+    /// Replaces the placeholders of a snapshot name template.
+    fn snapshot_file_name(&self, template: &str) -> String {
+        template
+            .replace("{market}", &self.config.market_name)
+            .replace("{tick}", &self.time.to_string())
+            .replace(
+                "{timestamp}",
+                &Local::now().format("%Y%m%d%H%M%S%3f").to_string(),
+            )
+    }
+
+    /// Remembers a snapshot written from the template and deletes the ones retention doesn't keep.
+    fn record_snapshot(&self, path: PathBuf) {
+        let mut history = self.snapshot_history.borrow_mut();
+        //a template without {tick} or {timestamp} overwrites the same file
+        history.retain(|(_, old_path)| *old_path != path);
+        history.push((self.time, path));
+
+        let keep_last = match self.config.snapshot_keep_last {
+            Some(keep_last) => keep_last,
+            None => return,
+        };
+        let len = history.len();
+        let mut kept = vec![];
+        for (i, (tick, path)) in history.drain(..).enumerate() {
+            let recent = i + keep_last >= len;
+            let milestone =
+                matches!(self.config.snapshot_keep_every, Some(every) if tick % every == 0);
+            if recent || milestone {
+                kept.push((tick, path));
+            } else if let Err(err) = remove_file(&path) {
+                println!(
+                    "Couldn't delete old snapshot {}, check error below:\n{:?}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+        *history = kept;
+    }
+
+    /// Writes the snapshot file, returns whether it succeeded.
+    fn write_snapshot(&self, filename: &str) -> bool {
+        let path: PathBuf = self.config.snapshot_dir.join(filename);
+        //copy market values to save to market snapshot
+        let snapshot = MarketSnapshot {
+            version: SNAPSHOT_VERSION,
+            goods: self.goods.clone(),
+            time: self.time,
+            last_trader_interaction: self.last_trader_interaction,
+            buy_contracts_archive: self.buy_contracts_archive.clone(),
+            sell_contracts_archive: self.sell_contracts_archive.clone(),
+            rng: self.config.seed.map(|_| self.rng.clone()),
+        };
+        let json_parser_result = serde_json::to_string(&snapshot);
+        if let Ok(snapshot_json) = json_parser_result {
+            if let Err(err) = create_dir_all(&self.config.snapshot_dir) {
+                println!(
+                    "Couldn't create snapshot directory, check error below:\n{:?}",
+                    err
+                );
+            } else {
+                //write a temporary file and rename it, so a crash never leaves a truncated snapshot
+                let tmp_path = self.config.snapshot_dir.join(format!("{}.tmp", filename));
+                let file_res = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&tmp_path);
+                if let Ok(mut file) = file_res {
+                    let write_res = file
+                        .write_all(snapshot_json.as_bytes())
+                        .and_then(|_| file.sync_all())
+                        .and_then(|_| rename(&tmp_path, &path));
+                    if let Err(err) = write_res {
+                        println!(
+                            "Couldn't write in snapshot file, check error below:\n{:?}",
+                            err
+                        );
+                        let _ = remove_file(&tmp_path);
+                    } else {
+                        return true;
+                    }
+                } else if let Err(err) = file_res {
+                    println!(
+                        "Couldn't create snapshot file, check error below:\n{:?}",
+                        err
+                    );
+                }
+            }
+        } else if let Err(err) = json_parser_result {
+            println!("Serde couldn't parse the market snapshot:\n{:?}", err);
+        }
+        false
+    }
+
+    fn open_log_sink(config: &MarketConfig) -> Box<dyn LogSink> {
+        match config.log_sink.sink(&config.log_path) {
+            Ok(log_sink) => log_sink,
+            Err(err) => {
+                //a market that can't open its log file can still trade, log to stderr instead
+                println!(
+                    "Couldn't open log file {}, logging to stderr, check error below:\n{:?}",
+                    config.log_path.display(),
+                    err
+                );
+                Box::new(StderrLogSink)
+            }
+        }
+    }
+
+    fn write_log(&self, record: LogRecord) {
+        if self.config.log_format == LogFormat::Legacy && !record.op.in_legacy_log() {
+            return;
+        }
+        //failed operations don't change the market, they are over once they are logged
+        let failed = record.outcome == LogOutcome::Error;
+        let line = self.log_line(record);
+        self.write_log_line(&line);
+        self.last_log_line_len.set(line.len());
+        if failed {
+            self.rotate_log_if_due();
+        }
+    }
+
+    fn write_log_line(&self, line: &str) {
+        if self.log_sink.borrow_mut().write_line(line).is_err() {
+            println!("{}: Couldn't write to log", self.get_name())
+        }
+    }
+
+    fn log_line(&self, mut record: LogRecord) -> String {
+        //YY:MM:DD:HH:MM:SEC:MSES
+        let timestamp = Local::now().format("%y:%m:%d:%H:%M:%S:%3f").to_string();
+        match self.config.log_format {
+            LogFormat::Legacy => format!(
+                "{}|{}|{}",
+                self.get_name(),
+                timestamp,
+                record.legacy_entry()
+            ),
+            LogFormat::JsonLines => {
+                record.market = self.get_name().to_string();
+                record.timestamp = match self.config.seed {
+                    //a seeded run must write the same json log every time
+                    Some(_) => self.time.to_string(),
+                    None => timestamp,
+                };
+                record.tick = self.time;
+                //a record only holds strings and numbers, serialization can't fail
+                serde_json::to_string(&record).unwrap()
+            }
+        }
+    }
+
+    fn rotate_log_if_due(&self) {
+        let rotate_by_ticks = self
+            .config
+            .log_rotate_every_ticks
+            .is_some_and(|ticks| self.time / ticks > self.log_started_at.get() / ticks);
+        let rotate_by_size = match (self.config.log_max_bytes, self.log_sink.borrow().size()) {
+            //+1 for the newline, the next entry is expected to be about as long as the last one
+            (Some(max_bytes), Some(size)) => {
+                size > 0 && size + self.last_log_line_len.get() as u64 + 1 > max_bytes
+            }
+            _ => false,
+        };
+        if rotate_by_ticks || rotate_by_size {
+            self.rotate_log();
+        }
+    }
+
+    fn rotate_log(&self) {
+        self.log_started_at.set(self.time);
+        let rotated = self
+            .log_sink
+            .borrow_mut()
+            .rotate(self.config.log_keep_rotated);
+        match rotated {
+            Ok(true) => {
+                //every file starts with the state of the market, in JSON lines it can be replayed on its own
+                self.write_log_line(&self.log_line(self.market_state_record()));
+            }
+            Ok(false) => {}
+            Err(err) => println!("Couldn't rotate log, check error below:\n{:?}", err),
+        }
+    }
+
+    fn market_init_record(&self, state: Option<MarketState>) -> LogRecord {
+        LogRecord::market_initialization(
+            InitialQuantities {
+                eur: self.goods.get(&GoodKind::EUR).unwrap().quantity.to_f32(),
+                yen: self.goods.get(&GoodKind::YEN).unwrap().quantity.to_f32(),
+                usd: self.goods.get(&GoodKind::USD).unwrap().quantity.to_f32(),
+                yuan: self.goods.get(&GoodKind::YUAN).unwrap().quantity.to_f32(),
+            },
+            state,
+        )
+    }
+
+    /// `MARKET_INITIALIZATION` record with everything a replay needs to continue from here.
+    fn market_state_record(&self) -> LogRecord {
+        let goods = [GoodKind::EUR, GoodKind::YEN, GoodKind::USD, GoodKind::YUAN]
+            .iter()
+            .map(|kind| self.goods.get(kind).unwrap().clone())
+            .collect();
+        let mut locks = vec![];
+        for (side, archive) in [
+            (LockSide::Buy, &self.buy_contracts_archive),
+            (LockSide::Sell, &self.sell_contracts_archive),
+        ] {
+            let mut contracts: Vec<&LockContract> = archive.contracts_by_token.values().collect();
+            contracts.sort_by(|a, b| a.token.cmp(&b.token));
+            locks.extend(contracts.into_iter().map(|contract| LoggedLock {
+                side,
+                token: contract.token.clone(),
+                trader_name: contract.trader_name.clone(),
+                kind: contract.kind,
+                quantity: contract.quantity,
+                price: contract.price,
+                expiry_time: contract.expiry_time,
+                renewals: contract.renewals,
+            }));
+        }
+        self.market_init_record(Some(MarketState {
+            tick: self.time,
+            last_trader_interaction: self.last_trader_interaction,
+            goods,
+            locks,
+        }))
+    }
+
+    fn write_log_market_init(&self) {
+        self.write_log(self.market_init_record(None));
+    }
+
+    fn write_log_market_state(&self) {
+        self.write_log(self.market_state_record());
+    }
+
+    fn write_log_buy_ok(
+        &self,
+        trader_name: String,
+        kind_to_buy: GoodKind,
+        quantity_to_buy: f32,
+        bid: f32,
+        token: &str,
+    ) {
+        self.write_log(LogRecord::lock(
+            LogOp::LockBuy,
+            trader_name,
+            kind_to_buy,
+            quantity_to_buy,
+            bid,
+            Some(token),
+            None,
+        ));
+    }
+
+    fn write_log_lock_buy_error(
+        &self,
+        trader_name: String,
+        kind_to_buy: GoodKind,
+        quantity_to_buy: f32,
+        bid: f32,
+        error: &dyn fmt::Debug,
+    ) {
+        self.write_log(LogRecord::lock(
+            LogOp::LockBuy,
+            trader_name,
+            kind_to_buy,
+            quantity_to_buy,
+            bid,
+            None,
+            Some(error),
+        ));
+    }
+
+    fn write_log_sell_ok(
+        &self,
+        trader_name: String,
+        kind_to_sell: GoodKind,
+        quantity_to_sell: f32,
+        offer: f32,
+        token: &str,
+    ) {
+        self.write_log(LogRecord::lock(
+            LogOp::LockSell,
+            trader_name,
+            kind_to_sell,
+            quantity_to_sell,
+            offer,
+            Some(token),
+            None,
+        ));
+    }
+
+    fn write_log_lock_sell_error(
+        &self,
+        trader_name: String,
+        kind_to_sell: GoodKind,
+        quantity_to_sell: f32,
+        offer: f32,
+        error: &dyn fmt::Debug,
+    ) {
+        self.write_log(LogRecord::lock(
+            LogOp::LockSell,
+            trader_name,
+            kind_to_sell,
+            quantity_to_sell,
+            offer,
+            None,
+            Some(error),
+        ));
+    }
+
+    fn write_log_expiry(&self, op: LogOp, contract: &LockContract) {
+        self.write_log(LogRecord::expiry(
+            op,
+            &contract.token,
+            contract.kind,
+            contract.quantity.to_f32(),
+            contract.price.to_f32(),
+        ));
+    }
+
+    fn write_log_sell_error(&self, token: &str, error: &dyn fmt::Debug) {
+        self.write_log(LogRecord::token_op(LogOp::Sell, token, Some(error)));
+    }
+
+    fn write_log_buy_error(&self, token: &str, error: &dyn fmt::Debug) {
+        self.write_log(LogRecord::token_op(LogOp::Buy, token, Some(error)));
+    }
+}
+
+impl Drop for FskMarket {
+    fn drop(&mut self) {
+        if self.config.snapshot_on_drop {
+            //open locks are saved in the snapshot, their tokens stay valid after a restart
+            self.take_snapshot(self.snapshot_file_name(&self.config.drop_snapshot_name_template))
+        }
+    }
+}
+
+impl Notifiable for FskMarket {
+    fn add_subscriber(&mut self, subscriber: Box<dyn Notifiable>) {
+        self.subs.push(subscriber);
+    }
+
+    fn on_event(&mut self, _event: Event) {
+        // here we apply logic of changing good quantities, as described in https://github.com/orgs/WG-AdvancedProgramming/discussions/38#discussioncomment-4167913
+        //every event triggers a new tick
+        self.time += 1;
+        let greediness = self.market_greediness();
+        /*         match event.kind {
+            EventKind::LockedBuy => {}
+            EventKind::Bought => {}
+            EventKind::LockedSell => {}
+            EventKind::Sold => {}
+            EventKind::Wait => {}
+        } */
+
+        //check when last trader interaction in our market was
+        let inactive_ticks = self.time - self.last_trader_interaction;
+        if inactive_ticks > self.config.trader_inactivity_time {
+            //if it was too long ago, we decrease exchange rates
+            //decrease exchange rate over time
+            let factor = self.config.exchange_rate_change_rate_over_time;
+            for (good_kind, reserve) in &mut self.goods {
+                match *good_kind {
+                    DEFAULT_GOOD_KIND => {}
+                    _ => {
+                        reserve.exchange_rate_buy =
+                            reserve.exchange_rate_buy * Fixed::from_f32(factor);
+                        reserve.exchange_rate_sell = self
+                            .pricing
+                            .sell_rate(reserve.exchange_rate_buy, greediness)
+                    }
+                }
+            }
+            self.write_log(LogRecord::rate_change(LogOp::InactivityDecay, factor));
+            self.notify_fsk(FskEvent::RatesDecayed {
+                factor,
+                inactive_ticks,
+            });
+        }
+
+        //restore locked default currency for expired sell
+        while let Some(expired_contract) = self.sell_contracts_archive.pop_expired(self.time) {
+            self.goods.get_mut(&DEFAULT_GOOD_KIND).unwrap().quantity += expired_contract.price;
+            self.write_log_expiry(LogOp::LockSellExpired, &expired_contract);
+            self.notify_fsk(FskEvent::LockSellExpired {
+                token: expired_contract.token,
+                good_kind: expired_contract.kind,
+                quantity: expired_contract.quantity.to_f32(),
+                price: expired_contract.price.to_f32(),
+            });
+        }
+
+        //restore locked good for expired buyout
+        while let Some(expired_contract) = self.buy_contracts_archive.pop_expired(self.time) {
+            self.goods.get_mut(&expired_contract.kind).unwrap().quantity +=
+                expired_contract.quantity;
+            self.write_log_expiry(LogOp::LockBuyExpired, &expired_contract);
+            self.notify_fsk(FskEvent::LockBuyExpired {
+                token: expired_contract.token,
+                good_kind: expired_contract.kind,
+                quantity: expired_contract.quantity.to_f32(),
+                price: expired_contract.price.to_f32(),
+            });
+        }
+
+        let ttl = self.config.retired_token_ttl;
+        self.buy_contracts_archive.forget_retired(self.time, ttl);
+        self.sell_contracts_archive.forget_retired(self.time, ttl);
+
+        //black_friday_handling
+        let period = self.config.black_friday_period;
+        let black_friday_start = self.config.black_friday_start;
+        let black_friday_end = (black_friday_start + self.config.black_friday_duration) % period;
+        let discount = self.config.black_friday_discount;
+        let discounted = Fixed::ONE - Fixed::from_f32(discount);
+        //black friday begins
+        if self.time % period == black_friday_start {
+            for (good_kind, reserve) in &mut self.goods {
+                match *good_kind {
+                    DEFAULT_GOOD_KIND => {}
+                    _ => {
+                        reserve.exchange_rate_buy = reserve.exchange_rate_buy * discounted;
+                        reserve.exchange_rate_sell = self
+                            .pricing
+                            .sell_rate(reserve.exchange_rate_buy, greediness)
+                    }
+                }
+            }
+            self.write_log(LogRecord::rate_change(
+                LogOp::BlackFridayStart,
+                1. - discount,
+            ));
+            self.notify_fsk(FskEvent::BlackFridayStarted { discount });
+        }
+        //black friday ends
+        if self.time % period == black_friday_end {
+            for (good_kind, reserve) in &mut self.goods {
+                match *good_kind {
+                    DEFAULT_GOOD_KIND => {}
+                    _ => {
+                        reserve.exchange_rate_buy = reserve.exchange_rate_buy / discounted;
+                        reserve.exchange_rate_sell = self
+                            .pricing
+                            .sell_rate(reserve.exchange_rate_buy, greediness)
+                    }
+                }
+            }
+            self.write_log(LogRecord::rate_change(
+                LogOp::BlackFridayEnd,
+                1. / (1. - discount),
+            ));
+            self.notify_fsk(FskEvent::BlackFridayEnded { discount });
+        }
+
+        //take snapshot and save to file for visualizer
+        let traded = std::mem::take(&mut self.trade_settled);
+        if self.snapshot_due(traded) {
+            self.take_snapshot(String::new());
+        }
+
+        self.finish_operation("on_event");
+    }
+}
+
+#[allow(unused_must_use)]
+impl Market for FskMarket {
+    fn new_random() -> Rc<RefCell<dyn Market>>
+    where
+        Self: Sized,
+    {
+        let quantities = FskMarket::random_quantities(&mut rand::thread_rng());
+        FskMarket::new_with_quantities(
+            quantities.eur,
+            quantities.yen,
+            quantities.usd,
+            quantities.yuan,
+        )
+    }
+
+    fn new_with_quantities(eur: f32, yen: f32, usd: f32, yuan: f32) -> Rc<RefCell<dyn Market>>
+    where
+        Self: Sized,
+    {
+        FskMarket::with_config(eur, yen, usd, yuan, MarketConfig::default())
+    }
+
+    fn new_file(path: &str) -> Rc<RefCell<dyn Market>>
+    where
+        Self: Sized,
+    {
+        if !Path::new(path).exists() {
+            return FskMarket::new_random();
+        }
+        match FskMarket::try_from_snapshot(path) {
+            Ok(market) => market,
+            Err(err) => {
+                println!(
+                    "Couldn't restore the market snapshot, check error below:\n{}",
+                    err
+                );
+                //in case of error just return a random market
+                FskMarket::new_random()
+            }
+        }
+    }
+
+    fn get_name(&self) -> &'static str {
+        "FSK"
+    }
+
+    fn get_budget(&self) -> f32 {
+        self.eur_reserve().to_f32()
+    }
+
+    fn get_buy_price(&self, kind: GoodKind, quantity: f32) -> Result<f32, MarketGetterError> {
+        self.buy_price(kind, Fixed::from_f32(quantity))
+            .map(Fixed::to_f32)
+    }
+
+    fn get_sell_price(&self, kind: GoodKind, quantity: f32) -> Result<f32, MarketGetterError> {
+        self.sell_price(kind, Fixed::from_f32(quantity))
+            .map(Fixed::to_f32)
+    }
+
+    fn get_goods(&self) -> Vec<GoodLabel> {
+        let eur_reserve = self.eur_reserve();
+        let mut res = Vec::new();
+        for (gk, reserve) in &self.goods {
+            let mut quoted_reserve = reserve.clone();
+            if *gk != DEFAULT_GOOD_KIND {
+                self.pricing.refresh_rates(
+                    &mut quoted_reserve,
+                    eur_reserve,
+                    self.market_greediness(),
+                );
+                quoted_reserve.exchange_rate_buy =
+                    self.pricing.quoted_buy_rate(&quoted_reserve, eur_reserve);
+            }
+            res.push(quoted_reserve.to_label());
+        }
+        res
+    }
+
+    /// `LockBuyError` has no quota variant, a lock that would break a cap of the config fails with
+    /// `InsufficientGoodQuantityAvailable` and `available_good_quantity` set to
+    /// - 0 if the trader already holds `max_locks_per_trader` locks;
+    /// - the quantity of the good the trader can still lock, for `max_locked_quantity_per_good`;
+    /// - the share of `quantity_to_buy` the EUR the trader can still lock pay for at the price of
+    ///   `bid`, for `max_locked_eur_per_trader`.
+    fn lock_buy(
+        &mut self,
+        kind_to_buy: GoodKind,
+        quantity_to_buy: f32,
+        bid: f32,
+        trader_name: String,
+    ) -> Result<String, LockBuyError> {
+        self.try_lock_buy(kind_to_buy, quantity_to_buy, bid, trader_name)
+            .map_err(|err| match err {
+                LockError::Market(err) => err,
+                LockError::Quota(quota) => LockBuyError::InsufficientGoodQuantityAvailable {
+                    requested_good_kind: kind_to_buy,
+                    requested_good_quantity: quantity_to_buy,
+                    available_good_quantity: quota.available_to_buy(quantity_to_buy, bid),
+                },
+            })
+    }
+
+    /// In `TokenMode::TraderBound` every token fails with `UnrecognizedToken`: `buy` can't tell who
+    /// is settling, a live lock stays open and can still be settled by `buy_as`. The log records
+    /// `TraderBoundToken` for tokens this market issued.
+    fn buy(&mut self, token: String, cash: &mut Good) -> Result<Good, BuyError> {
+        //trader-bound tokens can only be settled by buy_as, which knows who is settling
+        if self.config.token_mode == TokenMode::TraderBound {
+            let err = BuyError::UnrecognizedToken {
+                unrecognized_token: token.clone(),
+            };
+            //the lock stays open, only the log tells a token of this market from an unknown one
+            let known = self
+                .buy_contracts_archive
+                .token_status(&token, LockSide::Buy, self.time)
+                .is_some();
+            match known {
+                true => self.write_log_buy_error(&token, &TraderBoundToken),
+                false => self.write_log_buy_error(&token, &err),
+            }
+            return Err(err);
+        }
+        self.settle_buy(token, cash)
+    }
+
+    /// `LockSellError` has no quota variant, a lock that would break a cap of the config fails with
+    /// `InsufficientDefaultGoodQuantityAvailable` and `available_good_quantity` set to
+    /// - 0 if the trader already holds `max_locks_per_trader` locks;
+    /// - the share of `offer` the quantity of the good the trader can still lock is worth, for
+    ///   `max_locked_quantity_per_good`;
+    /// - the EUR the trader can still lock, for `max_locked_eur_per_trader`.
+    fn lock_sell(
+        &mut self,
+        kind_to_sell: GoodKind,
+        quantity_to_sell: f32,
+        offer: f32,
+        trader_name: String,
+    ) -> Result<String, LockSellError> {
+        self.try_lock_sell(kind_to_sell, quantity_to_sell, offer, trader_name)
+            .map_err(|err| match err {
+                LockError::Market(err) => err,
+                LockError::Quota(quota) => {
+                    LockSellError::InsufficientDefaultGoodQuantityAvailable {
+                        offered_good_kind: kind_to_sell,
+                        offered_good_quantity: quantity_to_sell,
+                        available_good_quantity: quota.available_to_sell(quantity_to_sell, offer),
+                    }
+                }
+            })
+    }
+
+    /// Like `buy`, in `TokenMode::TraderBound` every token fails with `UnrecognizedToken` and live
+    /// locks can still be settled by `sell_as`.
+    fn sell(&mut self, token: String, good: &mut Good) -> Result<Good, SellError> {
+        //trader-bound tokens can only be settled by sell_as, which knows who is settling
+        if self.config.token_mode == TokenMode::TraderBound {
+            let err = SellError::UnrecognizedToken {
+                unrecognized_token: token.clone(),
+            };
+            //the lock stays open, only the log tells a token of this market from an unknown one
+            let known = self
+                .sell_contracts_archive
+                .token_status(&token, LockSide::Sell, self.time)
+                .is_some();
+            match known {
+                true => self.write_log_sell_error(&token, &TraderBoundToken),
+                false => self.write_log_sell_error(&token, &err),
+            }
+            return Err(err);
+        }
+        self.settle_sell(token, good)
+    }
 }
@@ -0,0 +1,594 @@
+use crate::config::InitialQuantities;
+use crate::{Fixed, LockSide, Reserve};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use unitn_market_2022::good::good_kind::GoodKind;
+
+/// Format of the lines written to the market log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// `FSK|timestamp|LOCK_BUY-trader-KIND_TO_BUY:...`, the format expected by the course log checker.
+    ///
+    /// Only operations the checker knows are written, see `LogOp::in_legacy_log`.
+    #[default]
+    Legacy,
+    /// One `LogRecord` serialized as a JSON object per line.
+    JsonLines,
+}
+
+/// Built-in log sinks, selectable through `MarketConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogSinkMode {
+    /// Append to `MarketConfig::log_path`.
+    #[default]
+    File,
+    Stderr,
+    /// Don't log at all.
+    Null,
+}
+
+impl LogSinkMode {
+    pub fn sink(&self, log_path: &Path) -> io::Result<Box<dyn LogSink>> {
+        Ok(match self {
+            LogSinkMode::File => Box::new(FileLogSink::open(log_path)?),
+            LogSinkMode::Stderr => Box::new(StderrLogSink),
+            LogSinkMode::Null => Box::new(NullLogSink),
+        })
+    }
+}
+
+/// Where `FskMarket` writes its log.
+///
+/// `line` is a whole entry without the trailing newline. Legacy `MARKET_INITIALIZATION` entries
+/// span several lines but are still written with a single call.
+pub trait LogSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()>;
+
+    /// Bytes in the current file, `None` for sinks that don't write to a file.
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Moves the current file aside and starts a new one, keeping the `keep` most recent old files.
+    /// `None` keeps every old file.
+    ///
+    /// Returns whether the sink was rotated, sinks that don't write to a file can't be.
+    fn rotate(&mut self, _keep: Option<usize>) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Appends every entry to a file, the default sink.
+///
+/// Rotated files are named after the log file with a suffix, `.1` being the most recent one.
+#[derive(Debug)]
+pub struct FileLogSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl FileLogSink {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileLogSink> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(FileLogSink {
+            path: path.as_ref().to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+}
+
+impl LogSink for FileLogSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let line = format!("{}\n", line);
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    fn rotate(&mut self, keep: Option<usize>) -> io::Result<bool> {
+        let mut rotated_files = 0;
+        while self.rotated_path(rotated_files + 1).exists() {
+            rotated_files += 1;
+        }
+        let keep = keep.unwrap_or(rotated_files + 1);
+        //every old file moves up by one, drop the ones that would go past `keep`
+        for index in (keep.max(1)..=rotated_files).rev() {
+            fs::remove_file(self.rotated_path(index))?;
+        }
+        for index in (1..keep.min(rotated_files + 1)).rev() {
+            fs::rename(self.rotated_path(index), self.rotated_path(index + 1))?;
+        }
+        if keep > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        let path = self.path.clone();
+        *self = FileLogSink::open(path)?;
+        Ok(true)
+    }
+}
+
+/// Keeps the entries in memory.
+///
+/// Clones share the same entries: keep a clone and hand the other one to the market to read what
+/// it logged.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLogSink {
+    lines: Rc<RefCell<Vec<String>>>,
+}
+
+impl MemoryLogSink {
+    pub fn new() -> MemoryLogSink {
+        MemoryLogSink::default()
+    }
+
+    /// The entries written so far, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.borrow().clone()
+    }
+
+    /// Returns the entries written so far and forgets them.
+    pub fn take(&self) -> Vec<String> {
+        std::mem::take(&mut self.lines.borrow_mut())
+    }
+}
+
+impl LogSink for MemoryLogSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.lines.borrow_mut().push(line.to_string());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StderrLogSink;
+
+impl LogSink for StderrLogSink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(io::stderr(), "{}", line)
+    }
+}
+
+/// Drops every entry.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullLogSink;
+
+impl LogSink for NullLogSink {
+    fn write_line(&mut self, _line: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogOp {
+    MarketInitialization,
+    LockBuy,
+    LockSell,
+    Buy,
+    Sell,
+    CancelLockBuy,
+    CancelLockSell,
+    ExtendLockBuy,
+    ExtendLockSell,
+    LockBuyExpired,
+    LockSellExpired,
+    BlackFridayStart,
+    BlackFridayEnd,
+    InactivityDecay,
+}
+
+impl LogOp {
+    /// Name of the operation in the legacy format.
+    fn legacy_name(&self) -> &'static str {
+        match self {
+            LogOp::MarketInitialization => "MARKET_INITIALIZATION",
+            LogOp::LockBuy => "LOCK_BUY",
+            LogOp::LockSell => "LOCK_SELL",
+            LogOp::Buy => "BUY",
+            LogOp::Sell => "SELL",
+            LogOp::CancelLockBuy => "CANCEL_LOCK_BUY",
+            LogOp::CancelLockSell => "CANCEL_LOCK_SELL",
+            LogOp::ExtendLockBuy => "EXTEND_LOCK_BUY",
+            LogOp::ExtendLockSell => "EXTEND_LOCK_SELL",
+            LogOp::LockBuyExpired => "LOCK_BUY_EXPIRED",
+            LogOp::LockSellExpired => "LOCK_SELL_EXPIRED",
+            LogOp::BlackFridayStart => "BLACK_FRIDAY_START",
+            LogOp::BlackFridayEnd => "BLACK_FRIDAY_END",
+            LogOp::InactivityDecay => "INACTIVITY_DECAY",
+        }
+    }
+
+    /// Whether the operation is written in `LogFormat::Legacy`. The course log checker only knows
+    /// the operations of the baseline market, the others are only in `LogFormat::JsonLines`.
+    pub fn in_legacy_log(&self) -> bool {
+        matches!(
+            self,
+            LogOp::MarketInitialization
+                | LogOp::LockBuy
+                | LogOp::LockSell
+                | LogOp::Buy
+                | LogOp::Sell
+        )
+    }
+
+    fn from_legacy_name(name: &str) -> Option<LogOp> {
+        [
+            LogOp::MarketInitialization,
+            LogOp::LockBuy,
+            LogOp::LockSell,
+            LogOp::Buy,
+            LogOp::Sell,
+            LogOp::CancelLockBuy,
+            LogOp::CancelLockSell,
+            LogOp::ExtendLockBuy,
+            LogOp::ExtendLockSell,
+            LogOp::LockBuyExpired,
+            LogOp::LockSellExpired,
+            LogOp::BlackFridayStart,
+            LogOp::BlackFridayEnd,
+            LogOp::InactivityDecay,
+        ]
+        .into_iter()
+        .find(|op| op.legacy_name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogOutcome {
+    Ok,
+    Error,
+}
+
+/// One entry of the market log. Fields that don't apply to `op` are `None`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub market: String,
+    pub timestamp: String,
+    pub tick: u64,
+    pub op: LogOp,
+    pub trader: Option<String>,
+    pub kind: Option<GoodKind>,
+    pub qty: Option<f32>,
+    pub price: Option<f32>,
+    pub token: Option<String>,
+    pub outcome: LogOutcome,
+    /// Name of the error variant, e.g. `BidTooLow`.
+    pub error: Option<String>,
+    /// Only set for `LogOp::MarketInitialization`.
+    pub initial_quantities: Option<InitialQuantities>,
+    /// Only set for `LogOp::MarketInitialization` entries of a market that didn't just start, like
+    /// the first entry of a rotated log or of a market restored from a snapshot. Only written in
+    /// `LogFormat::JsonLines`, legacy entries keep the layout the existing log checker reads.
+    pub state: Option<MarketState>,
+    /// Factor the buy exchange rates were multiplied by, only set for black friday and decay entries.
+    pub factor: Option<f32>,
+}
+
+impl LogRecord {
+    fn new(op: LogOp, error: Option<&dyn fmt::Debug>) -> LogRecord {
+        LogRecord {
+            market: String::new(),
+            timestamp: String::new(),
+            tick: 0,
+            op,
+            trader: None,
+            kind: None,
+            qty: None,
+            price: None,
+            token: None,
+            outcome: match error {
+                None => LogOutcome::Ok,
+                Some(_) => LogOutcome::Error,
+            },
+            error: error.map(error_variant),
+            initial_quantities: None,
+            state: None,
+            factor: None,
+        }
+    }
+
+    pub(crate) fn market_initialization(
+        quantities: InitialQuantities,
+        state: Option<MarketState>,
+    ) -> LogRecord {
+        LogRecord {
+            initial_quantities: Some(quantities),
+            state,
+            ..LogRecord::new(LogOp::MarketInitialization, None)
+        }
+    }
+
+    /// A `LockBuy` or `LockSell`, `token` is only known if the lock was created.
+    pub(crate) fn lock(
+        op: LogOp,
+        trader: String,
+        kind: GoodKind,
+        qty: f32,
+        price: f32,
+        token: Option<&str>,
+        error: Option<&dyn fmt::Debug>,
+    ) -> LogRecord {
+        LogRecord {
+            trader: Some(trader),
+            kind: Some(kind),
+            qty: Some(qty),
+            price: Some(price),
+            token: token.map(str::to_string),
+            ..LogRecord::new(op, error)
+        }
+    }
+
+    /// An operation on an existing lock, like `Buy` or `CancelLockSell`.
+    pub(crate) fn token_op(op: LogOp, token: &str, error: Option<&dyn fmt::Debug>) -> LogRecord {
+        LogRecord {
+            token: Some(token.to_string()),
+            ..LogRecord::new(op, error)
+        }
+    }
+
+    /// A lock that expired unclaimed, `kind`, `qty` and `price` are the terms of the lock.
+    pub(crate) fn expiry(
+        op: LogOp,
+        token: &str,
+        kind: GoodKind,
+        qty: f32,
+        price: f32,
+    ) -> LogRecord {
+        LogRecord {
+            token: Some(token.to_string()),
+            kind: Some(kind),
+            qty: Some(qty),
+            price: Some(price),
+            ..LogRecord::new(op, None)
+        }
+    }
+
+    /// A change of every buy exchange rate but EUR's that no trader caused.
+    pub(crate) fn rate_change(op: LogOp, factor: f32) -> LogRecord {
+        LogRecord {
+            factor: Some(factor),
+            ..LogRecord::new(op, None)
+        }
+    }
+
+    /// The entry in the legacy format, without the `FSK|timestamp|` prefix.
+    pub(crate) fn legacy_entry(&self) -> String {
+        let outcome = match self.outcome {
+            LogOutcome::Ok => "OK",
+            LogOutcome::Error => "ERROR",
+        };
+        let token = self.token.as_deref().unwrap_or_default();
+        match self.op {
+            LogOp::MarketInitialization => {
+                let quantities = self.initial_quantities.unwrap_or(InitialQuantities {
+                    eur: 0.,
+                    yen: 0.,
+                    usd: 0.,
+                    yuan: 0.,
+                });
+                //the legacy entry keeps the baseline layout, the state is only in JSON lines
+                format!(
+                    "\nMARKET_INITIALIZATION\nEUR: {:+e}\nUSD: {:+e}\nYEN: {:+e}\nYUAN: {:+e}\nEND_MARKET_INITIALIZATION",
+                    quantities.eur,
+                    quantities.usd,
+                    quantities.yen,
+                    quantities.yuan,
+                )
+            }
+            LogOp::LockBuy | LogOp::LockSell => {
+                let (kind_field, qty_field, price_field) = match self.op {
+                    LogOp::LockBuy => ("KIND_TO_BUY", "QUANTITY_TO_BUY", "BID"),
+                    _ => ("KIND_TO_SELL", "QUANTITY_TO_SELL", "OFFER"),
+                };
+                let entry = format!(
+                    "{}-{}-{}:{}-{}:{:+e}-{}:{:+e}",
+                    self.op.legacy_name(),
+                    self.trader.as_deref().unwrap_or_default(),
+                    kind_field,
+                    self.kind.map(|kind| kind.to_string()).unwrap_or_default(),
+                    qty_field,
+                    self.qty.unwrap_or_default(),
+                    price_field,
+                    self.price.unwrap_or_default(),
+                );
+                match self.outcome {
+                    LogOutcome::Ok => format!("{}-TOKEN:{}", entry, token),
+                    LogOutcome::Error => format!("{}-ERROR", entry),
+                }
+            }
+            LogOp::LockBuyExpired | LogOp::LockSellExpired => format!(
+                "{}-TOKEN:{}-KIND:{}-QUANTITY:{:+e}-PRICE:{:+e}",
+                self.op.legacy_name(),
+                token,
+                self.kind.map(|kind| kind.to_string()).unwrap_or_default(),
+                self.qty.unwrap_or_default(),
+                self.price.unwrap_or_default(),
+            ),
+            LogOp::BlackFridayStart | LogOp::BlackFridayEnd | LogOp::InactivityDecay => format!(
+                "{}-FACTOR:{:+e}",
+                self.op.legacy_name(),
+                self.factor.unwrap_or_default()
+            ),
+            _ => format!("{}-TOKEN:{}-{}", self.op.legacy_name(), token, outcome),
+        }
+    }
+
+    /// Parses an entry written by `legacy_entry`.
+    ///
+    /// The legacy format doesn't name errors, so `error` is always `None`.
+    pub(crate) fn from_legacy_entry(entry: &str) -> Result<LogRecord, String> {
+        if let Some(quantities) = entry.strip_prefix("\nMARKET_INITIALIZATION\n") {
+            let mut values = HashMap::new();
+            for line in quantities.lines() {
+                if line == "END_MARKET_INITIALIZATION" {
+                    let quantity = |kind: &str| {
+                        values
+                            .get(kind)
+                            .copied()
+                            .ok_or(format!("MARKET_INITIALIZATION has no {} quantity", kind))
+                    };
+                    return Ok(LogRecord::market_initialization(
+                        InitialQuantities {
+                            eur: quantity("EUR")?,
+                            yen: quantity("YEN")?,
+                            usd: quantity("USD")?,
+                            yuan: quantity("YUAN")?,
+                        },
+                        None,
+                    ));
+                }
+                let (kind, quantity) = line
+                    .split_once(": ")
+                    .ok_or(format!("unexpected MARKET_INITIALIZATION line {:?}", line))?;
+                values.insert(kind, parse_number(quantity)?);
+            }
+            return Err("MARKET_INITIALIZATION has no END_MARKET_INITIALIZATION".to_string());
+        }
+
+        let (op_name, rest) = entry
+            .split_once('-')
+            .ok_or(format!("unknown log entry {:?}", entry))?;
+        let op =
+            LogOp::from_legacy_name(op_name).ok_or(format!("unknown operation {:?}", op_name))?;
+        match op {
+            LogOp::MarketInitialization => {
+                Err("MARKET_INITIALIZATION has no quantities".to_string())
+            }
+            LogOp::LockBuy | LogOp::LockSell => {
+                let (kind_field, qty_field, price_field) = match op {
+                    LogOp::LockBuy => ("-KIND_TO_BUY:", "-QUANTITY_TO_BUY:", "-BID:"),
+                    _ => ("-KIND_TO_SELL:", "-QUANTITY_TO_SELL:", "-OFFER:"),
+                };
+                //the trader name is free text, the fields after it are looked up from the end
+                let missing = |field: &str| format!("{} entry has no {} field", op_name, field);
+                let (trader, rest) = rest.rsplit_once(kind_field).ok_or(missing(kind_field))?;
+                let (kind, rest) = rest.split_once(qty_field).ok_or(missing(qty_field))?;
+                let (qty, rest) = rest.split_once(price_field).ok_or(missing(price_field))?;
+                let (price, token, outcome) = match rest.strip_suffix("-ERROR") {
+                    Some(price) => (price, None, LogOutcome::Error),
+                    None => {
+                        let (price, token) =
+                            rest.rsplit_once("-TOKEN:").ok_or(missing("-TOKEN:"))?;
+                        (price, Some(token.to_string()), LogOutcome::Ok)
+                    }
+                };
+                Ok(LogRecord {
+                    trader: Some(trader.to_string()),
+                    kind: Some(parse_kind(kind)?),
+                    qty: Some(parse_number(qty)?),
+                    price: Some(parse_number(price)?),
+                    token,
+                    outcome,
+                    ..LogRecord::new(op, None)
+                })
+            }
+            LogOp::LockBuyExpired | LogOp::LockSellExpired => {
+                let missing = |field: &str| format!("{} entry has no {} field", op_name, field);
+                let rest = rest.strip_prefix("TOKEN:").ok_or(missing("TOKEN:"))?;
+                let (token, rest) = rest.split_once("-KIND:").ok_or(missing("-KIND:"))?;
+                let (kind, rest) = rest.split_once("-QUANTITY:").ok_or(missing("-QUANTITY:"))?;
+                let (qty, price) = rest.split_once("-PRICE:").ok_or(missing("-PRICE:"))?;
+                Ok(LogRecord::expiry(
+                    op,
+                    token,
+                    parse_kind(kind)?,
+                    parse_number(qty)?,
+                    parse_number(price)?,
+                ))
+            }
+            LogOp::BlackFridayStart | LogOp::BlackFridayEnd | LogOp::InactivityDecay => {
+                let factor = rest
+                    .strip_prefix("FACTOR:")
+                    .ok_or(format!("{} entry has no FACTOR: field", op_name))?;
+                Ok(LogRecord::rate_change(op, parse_number(factor)?))
+            }
+            _ => {
+                let (token, outcome) = rest
+                    .strip_prefix("TOKEN:")
+                    .and_then(|rest| rest.rsplit_once('-'))
+                    .ok_or(format!("{} entry has no token", op_name))?;
+                let outcome = match outcome {
+                    "OK" => LogOutcome::Ok,
+                    "ERROR" => LogOutcome::Error,
+                    other => return Err(format!("unknown outcome {:?}", other)),
+                };
+                Ok(LogRecord {
+                    token: Some(token.to_string()),
+                    outcome,
+                    ..LogRecord::new(op, None)
+                })
+            }
+        }
+    }
+}
+
+/// What a market holds at the start of a log, beyond the quantities of `InitialQuantities`.
+///
+/// Every value is exact, so a replayed market restored from it picks up exactly where the logging
+/// market was.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketState {
+    pub tick: u64,
+    pub last_trader_interaction: u64,
+    /// Every good with its exchange rates, EUR first.
+    pub goods: Vec<Reserve>,
+    /// Locks the market still holds goods for, including the ones that expire this tick.
+    pub locks: Vec<LoggedLock>,
+}
+
+/// A lock of `MarketState`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoggedLock {
+    pub side: LockSide,
+    pub token: String,
+    pub trader_name: String,
+    pub kind: GoodKind,
+    pub quantity: Fixed,
+    pub price: Fixed,
+    pub expiry_time: u64,
+    pub renewals: u32,
+}
+
+fn parse_number(value: &str) -> Result<f32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{:?} is not a number", value))
+}
+
+fn parse_kind(value: &str) -> Result<GoodKind, String> {
+    [GoodKind::EUR, GoodKind::YEN, GoodKind::USD, GoodKind::YUAN]
+        .into_iter()
+        .find(|kind| kind.to_string() == value)
+        .ok_or(format!("{:?} is not a good kind", value))
+}
+
+/// Name of the variant of an error enum, taken from its `Debug` output.
+pub(crate) fn error_variant(error: &dyn fmt::Debug) -> String {
+    format!("{:?}", error)
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
@@ -0,0 +1,250 @@
+use crate::Fixed;
+use serde::{Deserialize, Serialize};
+use unitn_market_2022::good::consts::DEFAULT_GOOD_KIND;
+use unitn_market_2022::good::good_kind::GoodKind;
+use unitn_market_2022::market::good_label::GoodLabel;
+
+/// Built-in pricing strategies, selectable through `MarketConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PricingMode {
+    #[default]
+    Default,
+    ConstantProduct,
+}
+
+impl PricingMode {
+    pub fn strategy(&self) -> Box<dyn PricingStrategy> {
+        match self {
+            PricingMode::Default => Box::new(DefaultPricing),
+            PricingMode::ConstantProduct => Box::new(ConstantProductPricing),
+        }
+    }
+}
+
+/// A good the market owns and its exchange rates, the exact counterpart of `GoodLabel`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reserve {
+    pub good_kind: GoodKind,
+    pub quantity: Fixed,
+    pub exchange_rate_buy: Fixed,
+    pub exchange_rate_sell: Fixed,
+}
+
+impl Reserve {
+    /// The label `get_goods` publishes for this reserve.
+    pub fn to_label(&self) -> GoodLabel {
+        GoodLabel {
+            good_kind: self.good_kind,
+            quantity: self.quantity.to_f32(),
+            exchange_rate_buy: self.exchange_rate_buy.to_f32(),
+            exchange_rate_sell: self.exchange_rate_sell.to_f32(),
+        }
+    }
+}
+
+/// Decides how `FskMarket` prices its goods and how exchange rates react to trades.
+///
+/// `eur_reserve` is always the quantity of default good the market owns.
+pub trait PricingStrategy {
+    /// EUR the market asks for `quantity` units of `good`.
+    fn buy_price(&self, good: &Reserve, eur_reserve: Fixed, quantity: Fixed) -> Fixed;
+
+    /// EUR the market offers for `quantity` units of `good`, before capping it to the budget.
+    fn sell_price(
+        &self,
+        good: &Reserve,
+        eur_reserve: Fixed,
+        quantity: Fixed,
+        market_greediness: Fixed,
+    ) -> Fixed;
+
+    /// Buy exchange rate published by `get_goods`.
+    fn quoted_buy_rate(&self, good: &Reserve, eur_reserve: Fixed) -> Fixed {
+        self.buy_price(good, eur_reserve, Fixed::ONE)
+    }
+
+    /// Updates the exchange rates of `good` once a trade has been settled.
+    ///
+    /// `traded_quantity` is the quantity that left the market, negative if it entered the market.
+    /// `good.quantity` is already the quantity after the trade.
+    fn update_rates(
+        &self,
+        good: &mut Reserve,
+        eur_reserve: Fixed,
+        traded_quantity: Fixed,
+        market_greediness: Fixed,
+    );
+
+    /// Brings the stored exchange rates of `good` in line with the current reserves.
+    ///
+    /// Called on every good after a trade is settled and on published labels, strategies whose
+    /// rates only move with trades can keep the default no-op.
+    fn refresh_rates(&self, _good: &mut Reserve, _eur_reserve: Fixed, _market_greediness: Fixed) {}
+
+    /// Sell exchange rate matching the given buy exchange rate.
+    fn sell_rate(&self, exchange_rate_buy: Fixed, market_greediness: Fixed) -> Fixed {
+        exchange_rate_buy / market_greediness
+    }
+}
+
+/// The original FSK pricing: exchange rates move by the ratio between the supply before and after
+/// a trade. Buying pays the rate the good will have after the purchase and selling is quoted at the
+/// sell rate the good will have after the sale, which is the same rate `update_rates` settles on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultPricing;
+
+impl DefaultPricing {
+    /// Buy exchange rate once `quantity_to_buy` units have been taken out of `current_quantity`.
+    ///
+    /// A negative `quantity_to_buy` gives the rate after that many units entered the market.
+    pub fn impacted_exchange_rate_buy(
+        current_exchange_rate_buy: Fixed,
+        current_quantity: Fixed,
+        quantity_to_buy: Fixed,
+    ) -> Fixed {
+        //with nothing in stock there is no supply to compare against
+        if current_quantity <= Fixed::ZERO {
+            return current_exchange_rate_buy;
+        }
+        let den = current_quantity - quantity_to_buy;
+        if den == Fixed::ZERO {
+            return Fixed::MAX;
+        }
+        current_exchange_rate_buy * current_quantity / den
+    }
+
+    /// Sell exchange rate once `quantity_to_sell` units have been added to `current_quantity`.
+    pub fn impacted_exchange_rate_sell(
+        current_exchange_rate_sell: Fixed,
+        current_quantity: Fixed,
+        quantity_to_sell: Fixed,
+    ) -> Fixed {
+        //mirror of the buy curve: the rate scales with the supply ratio as well
+        DefaultPricing::impacted_exchange_rate_buy(
+            current_exchange_rate_sell,
+            current_quantity,
+            -quantity_to_sell,
+        )
+    }
+}
+
+impl PricingStrategy for DefaultPricing {
+    fn buy_price(&self, good: &Reserve, _eur_reserve: Fixed, quantity: Fixed) -> Fixed {
+        DefaultPricing::impacted_exchange_rate_buy(good.exchange_rate_buy, good.quantity, quantity)
+            * quantity
+    }
+
+    fn sell_price(
+        &self,
+        good: &Reserve,
+        _eur_reserve: Fixed,
+        quantity: Fixed,
+        _market_greediness: Fixed,
+    ) -> Fixed {
+        DefaultPricing::impacted_exchange_rate_sell(
+            good.exchange_rate_sell,
+            good.quantity,
+            quantity,
+        ) * quantity
+    }
+
+    fn quoted_buy_rate(&self, good: &Reserve, _eur_reserve: Fixed) -> Fixed {
+        //price impact calculation
+        DefaultPricing::impacted_exchange_rate_buy(
+            good.exchange_rate_buy,
+            good.quantity,
+            Fixed::ONE,
+        )
+    }
+
+    fn update_rates(
+        &self,
+        good: &mut Reserve,
+        _eur_reserve: Fixed,
+        traded_quantity: Fixed,
+        market_greediness: Fixed,
+    ) {
+        //calculate new exchange_rate_buy given the quantity traded
+        good.exchange_rate_buy = DefaultPricing::impacted_exchange_rate_buy(
+            good.exchange_rate_buy,
+            good.quantity + traded_quantity,
+            traded_quantity,
+        );
+        //calculate new exchange_rate_sell given the new exchange_rate_buy
+        good.exchange_rate_sell = self.sell_rate(good.exchange_rate_buy, market_greediness);
+    }
+}
+
+/// Automated market maker pricing: every non-default good forms an x*y=k pool with the EUR reserve.
+///
+/// Prices follow the same curve in both directions, so buying and then selling back the same
+/// quantity only gains the market its greediness. Since prices only depend on the reserves,
+/// black friday and inactivity decay don't affect quotes in this mode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConstantProductPricing;
+
+impl ConstantProductPricing {
+    /// EUR that has to enter the pool to take `quantity` units out of it.
+    fn eur_in(good_reserve: Fixed, eur_reserve: Fixed, quantity: Fixed) -> Fixed {
+        let den = good_reserve - quantity;
+        if den <= Fixed::ZERO {
+            return Fixed::MAX;
+        }
+        eur_reserve * quantity / den
+    }
+
+    /// EUR that leaves the pool when `quantity` units are put in it.
+    fn eur_out(good_reserve: Fixed, eur_reserve: Fixed, quantity: Fixed) -> Fixed {
+        let den = good_reserve + quantity;
+        if den <= Fixed::ZERO {
+            return Fixed::ZERO;
+        }
+        eur_reserve * quantity / den
+    }
+}
+
+impl PricingStrategy for ConstantProductPricing {
+    fn buy_price(&self, good: &Reserve, eur_reserve: Fixed, quantity: Fixed) -> Fixed {
+        if good.good_kind == DEFAULT_GOOD_KIND {
+            return quantity * good.exchange_rate_buy;
+        }
+        ConstantProductPricing::eur_in(good.quantity, eur_reserve, quantity)
+    }
+
+    fn sell_price(
+        &self,
+        good: &Reserve,
+        eur_reserve: Fixed,
+        quantity: Fixed,
+        market_greediness: Fixed,
+    ) -> Fixed {
+        if good.good_kind == DEFAULT_GOOD_KIND {
+            return quantity * good.exchange_rate_sell;
+        }
+        ConstantProductPricing::eur_out(good.quantity, eur_reserve, quantity) / market_greediness
+    }
+
+    fn update_rates(
+        &self,
+        good: &mut Reserve,
+        eur_reserve: Fixed,
+        _traded_quantity: Fixed,
+        market_greediness: Fixed,
+    ) {
+        //the pool already moved along the curve, the new rates only depend on the reserves
+        self.refresh_rates(good, eur_reserve, market_greediness);
+    }
+
+    fn refresh_rates(&self, good: &mut Reserve, eur_reserve: Fixed, market_greediness: Fixed) {
+        if good.good_kind == DEFAULT_GOOD_KIND {
+            return;
+        }
+        //marginal price of the pool
+        good.exchange_rate_buy = if good.quantity > Fixed::ZERO {
+            eur_reserve / good.quantity
+        } else {
+            Fixed::MAX
+        };
+        good.exchange_rate_sell = self.sell_rate(good.exchange_rate_buy, market_greediness);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::log::{error_variant, LogOp, LogOutcome, LogRecord};
+use crate::{FskMarket, LockSide, MarketConfig, NullLogSink, SettleError, TokenMode};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use unitn_market_2022::event::event::{Event, EventKind};
+use unitn_market_2022::event::notifiable::Notifiable;
+use unitn_market_2022::good::consts::DEFAULT_GOOD_KIND;
+use unitn_market_2022::good::good::Good;
+use unitn_market_2022::market::Market;
+
+/// A parsed log entry.
+struct LogLine {
+    /// Line the entry starts at, counting from 1.
+    line: usize,
+    record: LogRecord,
+    /// Whether `record.tick` was read from the log, legacy entries don't have one.
+    timed: bool,
+}
+
+/// Outcome of `replay_log`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayReport {
+    /// Entries the replayed market agreed with.
+    pub replayed: usize,
+    /// The first entry the replayed market disagreed with, the replay stops there.
+    pub divergence: Option<Divergence>,
+}
+
+/// A log entry whose outcome the replayed market couldn't reproduce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Line the entry starts at, counting from 1.
+    pub line: usize,
+    pub record: LogRecord,
+    /// What the log says happened and what happened instead.
+    pub message: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {} ({}): {}",
+            self.line,
+            self.record.legacy_entry().trim_start(),
+            self.message
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+    /// The log file couldn't be read.
+    Io { path: String, message: String },
+    /// The log has an entry that can't be parsed or replayed.
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io { path, message } => {
+                write!(f, "couldn't read log file {}: {}", path, message)
+            }
+            ReplayError::Parse { line, message } => {
+                write!(f, "couldn't parse log line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Replays the log at `path`, see `replay_log`.
+pub fn replay_file<P: AsRef<Path>>(
+    path: P,
+    config: MarketConfig,
+) -> Result<ReplayReport, ReplayError> {
+    let content = fs::read_to_string(path.as_ref()).map_err(|err| ReplayError::Io {
+        path: path.as_ref().display().to_string(),
+        message: err.to_string(),
+    })?;
+    replay_log(&content, config)
+}
+
+/// Re-drives a market configured by `config` through the operations of a log, legacy or
+/// JSON-lines, and stops at the first entry whose outcome it can't reproduce.
+///
+/// Every `MARKET_INITIALIZATION` starts a new market. In JSON-lines logs, entries written at the
+/// start of a rotated log or by a market restored from a snapshot carry a `MarketState`, the
+/// replayed market is restored from it with the same locks and tokens. The others, and every
+/// legacy entry, only have the initial quantities: a legacy log can't be replayed across a
+/// rotation or a restart.
+/// Tokens of later locks are matched by the order locks were created in, since the replayed market
+/// issues its own.
+///
+/// JSON-lines entries carry the market tick, the replayed market waits until it reaches it. Legacy
+/// entries don't, so events that only ticked the market, like other markets' trades, are lost and
+/// locks may expire later than they did. Nor do legacy logs have cancellations and extensions, a
+/// lock cancelled or extended by the logging market stays as it was in the replayed one.
+pub fn replay_log(content: &str, config: MarketConfig) -> Result<ReplayReport, ReplayError> {
+    let entries = parse_log(content)?;
+    //the replay must not overwrite the snapshots of the market it investigates
+    let mut config = config;
+    config.snapshot_on_drop = false;
+    config.snapshot_every_ticks = None;
+    config.snapshot_on_trade = false;
+    config.snapshot_on_price_move = None;
+
+    let mut market: Option<Rc<RefCell<FskMarket>>> = None;
+    //logged token -> token issued by the replayed market
+    let mut tokens = HashMap::new();
+    for (replayed, entry) in entries.iter().enumerate() {
+        let record = &entry.record;
+        if let LogOp::MarketInitialization = record.op {
+            let quantities = record.initial_quantities.ok_or(ReplayError::Parse {
+                line: entry.line,
+                message: "MARKET_INITIALIZATION has no quantities".to_string(),
+            })?;
+            market = Some(match &record.state {
+                //rotated logs and restored markets start with the whole state of the market
+                Some(state) => {
+                    FskMarket::from_logged_state(state, config.clone(), Box::new(NullLogSink))
+                }
+                None => FskMarket::with_log_sink(
+                    quantities.eur,
+                    quantities.yen,
+                    quantities.usd,
+                    quantities.yuan,
+                    config.clone(),
+                    Box::new(NullLogSink),
+                ),
+            });
+            tokens.clear();
+        }
+        let mut market = market
+            .as_ref()
+            .ok_or(ReplayError::Parse {
+                line: entry.line,
+                message: "the log doesn't start with MARKET_INITIALIZATION".to_string(),
+            })?
+            .borrow_mut();
+
+        let mut result = Ok(());
+        if entry.timed {
+            result = wait_until(&mut market, record.tick);
+        }
+        if result.is_ok() && !matches!(record.op, LogOp::MarketInitialization) {
+            result = replay_entry(&mut market, record, &mut tokens);
+        }
+        if let Err(message) = result {
+            return Ok(ReplayReport {
+                replayed,
+                divergence: Some(Divergence {
+                    line: entry.line,
+                    record: record.clone(),
+                    message,
+                }),
+            });
+        }
+    }
+    Ok(ReplayReport {
+        replayed: entries.len(),
+        divergence: None,
+    })
+}
+
+fn parse_log(content: &str) -> Result<Vec<LogLine>, ReplayError> {
+    let mut entries = vec![];
+    let mut lines = content.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let line_number = index + 1;
+        let parse_error = |message: String| ReplayError::Parse {
+            line: line_number,
+            message,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with('{') {
+            let record = serde_json::from_str(line).map_err(|err| parse_error(err.to_string()))?;
+            entries.push(LogLine {
+                line: line_number,
+                record,
+                timed: true,
+            });
+            continue;
+        }
+
+        //MARKET|TIMESTAMP|ENTRY
+        let mut entry = line
+            .splitn(3, '|')
+            .nth(2)
+            .ok_or_else(|| parse_error("expected MARKET|TIMESTAMP|ENTRY".to_string()))?
+            .to_string();
+        //MARKET_INITIALIZATION entries start with a newline and end at END_MARKET_INITIALIZATION
+        if entry.is_empty() {
+            for (_, next) in lines.by_ref() {
+                entry.push('\n');
+                entry.push_str(next);
+                if next == "END_MARKET_INITIALIZATION" {
+                    break;
+                }
+            }
+        }
+        let record = LogRecord::from_legacy_entry(&entry).map_err(parse_error)?;
+        entries.push(LogLine {
+            line: line_number,
+            record,
+            timed: false,
+        });
+    }
+    Ok(entries)
+}
+
+fn wait_until(market: &mut FskMarket, tick: u64) -> Result<(), String> {
+    if market.time > tick {
+        return Err(format!(
+            "the log is at tick {}, the replayed market is already at tick {}",
+            tick, market.time
+        ));
+    }
+    while market.time < tick {
+        market.on_event(Event {
+            kind: EventKind::Wait,
+            good_kind: DEFAULT_GOOD_KIND,
+            quantity: 0.,
+            price: 0.,
+        });
+    }
+    Ok(())
+}
+
+/// Runs the operation of `record` on `market` and checks it has the logged outcome.
+fn replay_entry(
+    market: &mut FskMarket,
+    record: &LogRecord,
+    tokens: &mut HashMap<String, String>,
+) -> Result<(), String> {
+    let incomplete = |field: &str| format!("the entry has no {}", field);
+    let logged_token = record.token.clone().unwrap_or_default();
+    let token = tokens
+        .get(&logged_token)
+        .cloned()
+        .unwrap_or_else(|| logged_token.clone());
+    //the log doesn't record what a trader paid with, settle with what the replayed market expects
+    //unless the trader got it wrong
+    let pay_in_full = match record.outcome {
+        LogOutcome::Ok => true,
+        LogOutcome::Error => is_token_error(record.error.as_deref()),
+    };
+
+    let replayed: Result<Option<String>, String> = match record.op {
+        //the replayed market expires locks and moves its rates on its own as time goes by
+        LogOp::MarketInitialization
+        | LogOp::LockBuyExpired
+        | LogOp::LockSellExpired
+        | LogOp::BlackFridayStart
+        | LogOp::BlackFridayEnd
+        | LogOp::InactivityDecay => Ok(None),
+        LogOp::LockBuy | LogOp::LockSell => {
+            let trader = record.trader.clone().ok_or_else(|| incomplete("trader"))?;
+            let kind = record.kind.ok_or_else(|| incomplete("kind"))?;
+            let qty = record.qty.ok_or_else(|| incomplete("qty"))?;
+            let price = record.price.ok_or_else(|| incomplete("price"))?;
+            match record.op {
+                LogOp::LockBuy => market
+                    .lock_buy(kind, qty, price, trader)
+                    .map_err(|err| error_variant(&err)),
+                _ => market
+                    .lock_sell(kind, qty, price, trader)
+                    .map_err(|err| error_variant(&err)),
+            }
+            .map(Some)
+        }
+        LogOp::Buy | LogOp::Sell if record.error.as_deref() == Some(TRADER_BOUND_TOKEN) => {
+            let (archive, side) = match record.op {
+                LogOp::Buy => (&market.buy_contracts_archive, LockSide::Buy),
+                _ => (&market.sell_contracts_archive, LockSide::Sell),
+            };
+            let known = archive.token_status(&token, side, market.time).is_some();
+            let refused = match record.op {
+                LogOp::Buy => market
+                    .buy(token, &mut Good::new(DEFAULT_GOOD_KIND, 0.))
+                    .map(|_| None)
+                    .map_err(|err| error_variant(&err)),
+                _ => market
+                    .sell(token, &mut Good::new(DEFAULT_GOOD_KIND, 0.))
+                    .map(|_| None)
+                    .map_err(|err| error_variant(&err)),
+            };
+            //buy and sell refuse trader-bound tokens as unrecognized, only the log says they're known
+            let trader_bound = market.config.token_mode == TokenMode::TraderBound;
+            refused.map_err(|variant| match variant.as_str() {
+                "UnrecognizedToken" if known && trader_bound => TRADER_BOUND_TOKEN.to_string(),
+                _ => variant,
+            })
+        }
+        //settled as the trader who locked the token, so trader-bound tokens are accepted too
+        LogOp::Buy => {
+            let (trader, price) = match market.buy_contracts_archive.contracts_by_token.get(&token)
+            {
+                Some(contract) if pay_in_full => {
+                    (contract.trader_name.clone(), contract.price.to_f32())
+                }
+                Some(contract) => (contract.trader_name.clone(), 0.),
+                None => (String::new(), 0.),
+            };
+            market
+                .buy_as(trader, token, &mut Good::new(DEFAULT_GOOD_KIND, price))
+                .map(|_| None)
+                .map_err(|err| settle_error_variant(&err))
+        }
+        LogOp::Sell => {
+            let (trader, mut good) =
+                match market.sell_contracts_archive.contracts_by_token.get(&token) {
+                    Some(contract) if pay_in_full => (
+                        contract.trader_name.clone(),
+                        Good::new(contract.kind, contract.quantity.to_f32()),
+                    ),
+                    Some(contract) => (contract.trader_name.clone(), Good::new(contract.kind, 0.)),
+                    None => (String::new(), Good::new(DEFAULT_GOOD_KIND, 0.)),
+                };
+            market
+                .sell_as(trader, token, &mut good)
+                .map(|_| None)
+                .map_err(|err| settle_error_variant(&err))
+        }
+        LogOp::CancelLockBuy => market
+            .cancel_lock_buy(token)
+            .map(|_| None)
+            .map_err(|err| error_variant(&err)),
+        LogOp::CancelLockSell => market
+            .cancel_lock_sell(token)
+            .map(|_| None)
+            .map_err(|err| error_variant(&err)),
+        LogOp::ExtendLockBuy => market
+            .extend_lock_buy(token)
+            .map(|_| None)
+            .map_err(|err| error_variant(&err)),
+        LogOp::ExtendLockSell => market
+            .extend_lock_sell(token)
+            .map(|_| None)
+            .map_err(|err| error_variant(&err)),
+    };
+
+    match (record.outcome, replayed) {
+        (LogOutcome::Ok, Ok(replayed_token)) => {
+            if let Some(replayed_token) = replayed_token {
+                tokens.insert(logged_token, replayed_token);
+            }
+            Ok(())
+        }
+        (LogOutcome::Ok, Err(variant)) => Err(format!(
+            "the log says OK, the replayed market failed with {}",
+            variant
+        )),
+        (LogOutcome::Error, Ok(_)) => Err(format!(
+            "the log says {}, the replayed market succeeded",
+            record.error.as_deref().unwrap_or("ERROR")
+        )),
+        (LogOutcome::Error, Err(variant)) => match record.error.as_deref() {
+            //a trader's mistake can't be reproduced, only that the market refused the settlement
+            Some(logged)
+                if logged != variant.as_str()
+                    && (pay_in_full
+                        || is_token_error(Some(&variant))
+                        || !matches!(record.op, LogOp::Buy | LogOp::Sell)) =>
+            {
+                Err(format!(
+                    "the log says {}, the replayed market failed with {}",
+                    logged, variant
+                ))
+            }
+            _ => Ok(()),
+        },
+    }
+}
+
+fn settle_error_variant<E: fmt::Debug>(error: &SettleError<E>) -> String {
+    match error {
+        SettleError::Market(error) => error_variant(error),
+        error => error_variant(error),
+    }
+}
+
+/// Reason logged when `buy` or `sell` refuse a token of `TokenMode::TraderBound`.
+const TRADER_BOUND_TOKEN: &str = "TraderBoundToken";
+
+/// Whether `variant` is a `BuyError` or `SellError` about the token rather than the payment or the
+/// trader.
+fn is_token_error(variant: Option<&str>) -> bool {
+    matches!(variant, Some("UnrecognizedToken") | Some("ExpiredToken"))
+}
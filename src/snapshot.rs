@@ -0,0 +1,274 @@
+use crate::{ContractsArchive, Fixed, LockContract, Reserve};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use unitn_market_2022::good::good_kind::GoodKind;
+
+/// Version of the snapshots written by this market.
+///
+/// 1: goods, time and last_trader_interaction, written without a version field.
+/// 2: adds the buy and sell contracts archives.
+/// 3: retired tokens are stored with the tick they were retired at.
+/// 4: quantities, rates and prices are exact decimal strings, contracts store `kind` and `quantity`
+/// instead of a `good`.
+pub const SNAPSHOT_VERSION: u64 = 4;
+
+/// `MIGRATIONS[i]` upgrades a snapshot from version `i + 1` to version `i + 2`.
+const MIGRATIONS: [fn(&mut Map<String, Value>); (SNAPSHOT_VERSION - 1) as usize] =
+    [migrate_v1_to_v2, migrate_v2_to_v3, migrate_v3_to_v4];
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MarketSnapshot {
+    pub(crate) version: u64,
+    pub(crate) goods: HashMap<GoodKind, Reserve>,
+    pub(crate) time: u64,
+    pub(crate) last_trader_interaction: u64,
+    pub(crate) buy_contracts_archive: ContractsArchive,
+    pub(crate) sell_contracts_archive: ContractsArchive,
+    /// State of the token generator, only saved for markets with a `MarketConfig::seed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) rng: Option<ChaCha12Rng>,
+}
+
+impl MarketSnapshot {
+    /// Reads and validates the snapshot at `path`.
+    pub(crate) fn from_file(path: &Path) -> Result<MarketSnapshot, SnapshotError> {
+        let content = fs::read_to_string(path).map_err(|err| SnapshotError::Io {
+            path: path.display().to_string(),
+            message: err.to_string(),
+        })?;
+        let value: Value = serde_json::from_str(&content).map_err(|err| SnapshotError::Parse {
+            message: err.to_string(),
+        })?;
+        let mut snapshot: MarketSnapshot =
+            serde_json::from_value(migrate(value)?).map_err(|err| SnapshotError::Parse {
+                message: err.to_string(),
+            })?;
+        snapshot.validate()?;
+        snapshot.buy_contracts_archive.rebuild_indexes();
+        snapshot.sell_contracts_archive.rebuild_indexes();
+        Ok(snapshot)
+    }
+
+    /// Checks that the snapshot describes a market that can keep trading.
+    fn validate(&self) -> Result<(), SnapshotError> {
+        for kind in [GoodKind::EUR, GoodKind::YEN, GoodKind::USD, GoodKind::YUAN] {
+            let good = self.goods.get(&kind).ok_or(SnapshotError::Invalid {
+                message: format!("goods has no {} entry", kind),
+            })?;
+            if good.good_kind != kind {
+                return Err(SnapshotError::Invalid {
+                    message: format!("goods.{} holds {}", kind, good.good_kind),
+                });
+            }
+            if good.quantity < Fixed::ZERO {
+                return Err(SnapshotError::Invalid {
+                    message: format!("goods.{}.quantity is {}", kind, good.quantity),
+                });
+            }
+            let rates = [
+                ("exchange_rate_buy", good.exchange_rate_buy),
+                ("exchange_rate_sell", good.exchange_rate_sell),
+            ];
+            for (field, rate) in rates {
+                if rate <= Fixed::ZERO {
+                    return Err(SnapshotError::Invalid {
+                        message: format!("goods.{}.{} is {}", kind, field, rate),
+                    });
+                }
+            }
+        }
+        if self.last_trader_interaction > self.time {
+            return Err(SnapshotError::Invalid {
+                message: format!(
+                    "last_trader_interaction {} is after time {}",
+                    self.last_trader_interaction, self.time
+                ),
+            });
+        }
+        let archives = [
+            ("buy_contracts_archive", &self.buy_contracts_archive),
+            ("sell_contracts_archive", &self.sell_contracts_archive),
+        ];
+        for (archive_name, archive) in archives {
+            for (token, contract) in &archive.contracts_by_token {
+                validate_contract(archive_name, token, contract)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Upgrades a snapshot of any known version to `SNAPSHOT_VERSION`.
+fn migrate(value: Value) -> Result<Value, SnapshotError> {
+    let mut fields = match value {
+        Value::Object(fields) => fields,
+        _ => {
+            return Err(SnapshotError::Parse {
+                message: "a snapshot should be a JSON object".to_string(),
+            })
+        }
+    };
+    let version = match fields.get("version") {
+        //the first snapshots had no version field
+        None => 1,
+        Some(version) => version.as_u64().ok_or(SnapshotError::Parse {
+            message: format!("version should be a positive integer, found {}", version),
+        })?,
+    };
+    if version == 0 || version > SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion {
+            version,
+            latest: SNAPSHOT_VERSION,
+        });
+    }
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        migration(&mut fields);
+    }
+    fields.insert("version".to_string(), Value::from(SNAPSHOT_VERSION));
+    Ok(Value::Object(fields))
+}
+
+fn migrate_v1_to_v2(fields: &mut Map<String, Value>) {
+    //version 1 markets dropped every lock before taking a snapshot, the archives are only kept
+    //if the snapshot was written by a market that saved locks before versions were introduced
+    let empty_archive = serde_json::to_value(ContractsArchive::default()).unwrap();
+    for archive in ["buy_contracts_archive", "sell_contracts_archive"] {
+        fields
+            .entry(archive)
+            .or_insert_with(|| empty_archive.clone());
+    }
+}
+
+fn migrate_v2_to_v3(fields: &mut Map<String, Value>) {
+    //the retirement tick wasn't saved, the tokens are remembered as if retired at the snapshot time
+    let time = fields.get("time").cloned().unwrap_or(Value::from(0));
+    for archive in ["buy_contracts_archive", "sell_contracts_archive"] {
+        let archive = match fields.get_mut(archive) {
+            Some(Value::Object(archive)) => archive,
+            _ => continue,
+        };
+        for retired in [
+            "expired_contracts",
+            "consumed_contracts",
+            "cancelled_contracts",
+        ] {
+            if let Some(Value::Array(tokens)) = archive.get(retired) {
+                let tokens = tokens
+                    .iter()
+                    .filter_map(|token| Some((token.as_str()?.to_string(), time.clone())))
+                    .collect();
+                archive.insert(retired.to_string(), Value::Object(tokens));
+            }
+        }
+    }
+}
+
+fn migrate_v3_to_v4(fields: &mut Map<String, Value>) {
+    if let Some(Value::Object(goods)) = fields.get_mut("goods") {
+        for good in goods.values_mut() {
+            if let Value::Object(good) = good {
+                for field in ["quantity", "exchange_rate_buy", "exchange_rate_sell"] {
+                    fixed_field(good, field);
+                }
+            }
+        }
+    }
+    for archive in ["buy_contracts_archive", "sell_contracts_archive"] {
+        let contracts = match fields.get_mut(archive) {
+            Some(Value::Object(archive)) => archive.get_mut("contracts_by_token"),
+            _ => None,
+        };
+        let contracts = match contracts {
+            Some(Value::Object(contracts)) => contracts,
+            _ => continue,
+        };
+        for contract in contracts.values_mut() {
+            let contract = match contract {
+                Value::Object(contract) => contract,
+                _ => continue,
+            };
+            if let Some(Value::Object(mut good)) = contract.remove("good") {
+                for field in ["kind", "quantity"] {
+                    if let Some(value) = good.remove(field) {
+                        contract.insert(field.to_string(), value);
+                    }
+                }
+            }
+            for field in ["quantity", "price"] {
+                fixed_field(contract, field);
+            }
+        }
+    }
+}
+
+/// Replaces the number written for `field` with the decimal it stands for, to 9 places.
+///
+/// The number is taken as the `f64` the JSON holds: narrowing it to `f32` first would write the
+/// binary noise of the `f32` in as exact decimals.
+fn fixed_field(object: &mut Map<String, Value>, field: &str) {
+    if let Some(value) = object.get_mut(field) {
+        if let Some(number) = value.as_f64() {
+            *value = Value::from(Fixed::from_f64(number).to_string());
+        }
+    }
+}
+
+fn validate_contract(
+    archive_name: &str,
+    token: &str,
+    contract: &LockContract,
+) -> Result<(), SnapshotError> {
+    if contract.token != token {
+        return Err(SnapshotError::Invalid {
+            message: format!(
+                "{} stores contract {} under token {}",
+                archive_name, contract.token, token
+            ),
+        });
+    }
+    if contract.quantity < Fixed::ZERO || contract.price < Fixed::ZERO {
+        return Err(SnapshotError::Invalid {
+            message: format!(
+                "{} contract {} locks {} for {}",
+                archive_name, token, contract.quantity, contract.price
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    /// The snapshot file couldn't be read.
+    Io { path: String, message: String },
+    /// The snapshot file isn't a valid market snapshot.
+    Parse { message: String },
+    /// The snapshot was written by a newer market, or has a version that never existed.
+    UnsupportedVersion { version: u64, latest: u64 },
+    /// The snapshot was parsed but describes a market that can't be restored.
+    Invalid { message: String },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io { path, message } => {
+                write!(f, "couldn't read snapshot file {}: {}", path, message)
+            }
+            SnapshotError::Parse { message } => write!(f, "couldn't parse snapshot: {}", message),
+            SnapshotError::UnsupportedVersion { version, latest } => write!(
+                f,
+                "snapshot version {} is not supported, latest is {}",
+                version, latest
+            ),
+            SnapshotError::Invalid { message } => write!(f, "invalid snapshot: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
@@ -1,16 +1,64 @@
 #[cfg(test)]
 mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
     use unitn_market_2022::{
+        event::event::{Event, EventKind},
         good::{good::Good, good_kind::GoodKind},
-        market::{market_test, Market, SellError},
-        wait_one_day,
+        market::{market_test, Market},
     };
 
     //import here the market_test module and the Market trait
     //import here your implementation of the market
-    use super::super::FskMarket;
+    use super::super::{FskEvent, FskMarket, FskNotifiable};
     //make an alias to your market 37 TEST
     type MarketType = FskMarket;
+
+    //markets built by the tests check their invariants and leave nothing in the crate directory
+    fn quiet_config() -> super::super::MarketConfigBuilder {
+        use super::super::{LogSinkMode, MarketConfig};
+
+        MarketConfig::builder()
+            .log_sink(LogSinkMode::Null)
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+    }
+
+    //the market of most tests, 10000 units of every good
+    fn new_market(config: super::super::MarketConfig) -> Rc<RefCell<FskMarket>> {
+        FskMarket::with_config(10000., 10000., 10000., 10000., config)
+    }
+
+    //like new_market, logging to the given sink
+    fn new_logged_market(
+        config: super::super::MarketConfig,
+        log_sink: Box<dyn super::super::LogSink>,
+    ) -> Rc<RefCell<FskMarket>> {
+        FskMarket::with_log_sink(10000., 10000., 10000., 10000., config, log_sink)
+    }
+
+    //a directory of a test's own, unique to this run, that the test removes once done
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("market_fsk_{}_{}", name, std::process::id()))
+    }
+
+    //keeps every event told to FskNotifiable subscribers
+    struct Recorder(Rc<RefCell<Vec<FskEvent>>>);
+    impl FskNotifiable for Recorder {
+        fn on_fsk_event(&mut self, event: FskEvent) {
+            self.0.borrow_mut().push(event);
+        }
+    }
+
+    //an event that only moves the market time forward
+    fn wait() -> Event {
+        Event {
+            kind: EventKind::Wait,
+            good_kind: GoodKind::EUR,
+            quantity: 0.,
+            price: 0.,
+        }
+    }
     //test every aspect of your market using the generic function
     #[test]
     fn tests() {
@@ -65,6 +113,1780 @@ mod test {
         market_test::test_working_function_lock_sell_token::<MarketType>();
     }
 
+    #[test]
+    fn config_errors_name_the_offending_field() {
+        use super::super::{ConfigError, MarketConfig};
+
+        let unknown = MarketConfig::from_json_str(r#"{"market_greediness": 1.1, "greed": 2}"#);
+        assert_eq!(
+            unknown,
+            Err(ConfigError::UnknownField {
+                field: "greed".to_string()
+            })
+        );
+
+        let nested = MarketConfig::from_toml_str("[initial_quantities]\neur = 10.0\ngbp = 1.0\n");
+        assert_eq!(
+            nested,
+            Err(ConfigError::UnknownField {
+                field: "initial_quantities.gbp".to_string()
+            })
+        );
+
+        let out_of_range = MarketConfig::from_toml_str("lock_initial_ttl = 0\n");
+        assert!(matches!(
+            out_of_range,
+            Err(ConfigError::OutOfRange {
+                field: "lock_initial_ttl",
+                ..
+            })
+        ));
+
+        for name in ["../elsewhere", "a/b", "a\\b", ".."] {
+            assert_eq!(
+                MarketConfig::builder().market_name(name).build(),
+                Err(ConfigError::OutOfRange {
+                    field: "market_name",
+                    value: name.to_string(),
+                    expected: "a non-empty name without path separators or ..",
+                })
+            );
+        }
+
+        let config =
+            MarketConfig::from_toml_str("market_greediness = 1.5\nblack_friday_period = 10\n")
+                .unwrap();
+        assert_eq!(config.market_greediness(), 1.5);
+        assert_eq!(config.black_friday_period(), 10);
+        assert_eq!(config.lock_initial_ttl(), 9);
+        //retired tokens aren't remembered forever by default
+        assert_eq!(config.retired_token_ttl(), 1000);
+    }
+
+    #[test]
+    fn config_rejects_values_out_of_range() {
+        use super::super::{ConfigError, MarketConfig, MarketConfigBuilder};
+
+        let cases: Vec<(&str, MarketConfigBuilder)> = vec![
+            (
+                "lock_initial_ttl",
+                MarketConfig::builder().lock_initial_ttl(0),
+            ),
+            (
+                "market_greediness",
+                MarketConfig::builder().market_greediness(0.99),
+            ),
+            (
+                "market_greediness",
+                MarketConfig::builder().market_greediness(f32::NAN),
+            ),
+            (
+                "black_friday_discount",
+                MarketConfig::builder().black_friday_discount(1.),
+            ),
+            (
+                "black_friday_discount",
+                MarketConfig::builder().black_friday_discount(-0.1),
+            ),
+            (
+                "exchange_rate_change_rate_over_time",
+                MarketConfig::builder().exchange_rate_change_rate_over_time(0.),
+            ),
+            (
+                "exchange_rate_change_rate_over_time",
+                MarketConfig::builder().exchange_rate_change_rate_over_time(1.01),
+            ),
+            (
+                "black_friday_period",
+                MarketConfig::builder().black_friday_period(1),
+            ),
+            (
+                "black_friday_start",
+                MarketConfig::builder()
+                    .black_friday_period(5)
+                    .black_friday_start(5),
+            ),
+            (
+                "black_friday_duration",
+                MarketConfig::builder().black_friday_duration(0),
+            ),
+            (
+                "black_friday_duration",
+                MarketConfig::builder()
+                    .black_friday_period(5)
+                    .black_friday_duration(5),
+            ),
+        ];
+        for (field, builder) in cases {
+            assert!(
+                matches!(builder.build(), Err(ConfigError::OutOfRange { field: f, .. }) if f == field),
+                "{} should be out of range",
+                field
+            );
+        }
+
+        //the bounds themselves are allowed
+        MarketConfig::builder()
+            .lock_initial_ttl(1)
+            .market_greediness(1.)
+            .black_friday_discount(0.)
+            .exchange_rate_change_rate_over_time(1.)
+            .black_friday_period(2)
+            .black_friday_start(1)
+            .black_friday_duration(1)
+            .build()
+            .unwrap();
+    }
+
+    #[test]
+    fn non_default_config_changes_the_market() {
+        use super::super::TokenStatus;
+
+        let expires_in = |builder: super::super::MarketConfigBuilder| {
+            let market = new_market(builder.build().unwrap());
+            let mut market = market.borrow_mut();
+            let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+            let token = market
+                .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+                .unwrap();
+            match market.token_status(&token) {
+                TokenStatus::Active { expires_in, .. } => expires_in,
+                _ => panic!("the lock should be live"),
+            }
+        };
+        //locked at 0 and ticked to 1 by the lock notification
+        assert_eq!(expires_in(quiet_config()), 8);
+        assert_eq!(expires_in(quiet_config().lock_initial_ttl(2)), 1);
+
+        let sell_rate = |builder: super::super::MarketConfigBuilder| {
+            let market = new_market(builder.build().unwrap());
+            let market = market.borrow();
+            market
+                .get_goods()
+                .into_iter()
+                .find(|good_label| good_label.good_kind == GoodKind::USD)
+                .unwrap()
+                .exchange_rate_sell
+        };
+        //a greedier market pays less for what it buys back
+        assert!(sell_rate(quiet_config().market_greediness(2.)) < sell_rate(quiet_config()));
+    }
+
+    #[test]
+    fn default_pricing_matches_the_original_formulas() {
+        use super::super::{DefaultPricing, Fixed, PricingStrategy, Reserve};
+
+        //exchange rate once `traded` units left a supply of `quantity`, as get_buy_price,
+        //get_goods and update_price computed it before pricing was pluggable
+        let original_rate_buy =
+            |rate: f64, quantity: f64, traded: f64| rate * quantity / (quantity - traded);
+        let close = |actual: Fixed, expected: f64| {
+            (actual.to_f32() as f64 - expected).abs() < expected.abs() * 1e-6
+        };
+        let greediness = 1.25;
+        let usd = Reserve {
+            good_kind: GoodKind::USD,
+            quantity: Fixed::from_f32(8000.),
+            exchange_rate_buy: Fixed::from_f32(0.5),
+            exchange_rate_sell: Fixed::from_f32(0.4),
+        };
+        let eur = Fixed::from_f32(10000.);
+        let pricing = DefaultPricing;
+
+        for quantity in [1., 100., 2500., 7999.] {
+            let price = pricing.buy_price(&usd, eur, Fixed::from_f32(quantity));
+            assert!(close(
+                price,
+                original_rate_buy(0.5, 8000., quantity as f64) * quantity as f64
+            ));
+        }
+        assert!(close(
+            pricing.quoted_buy_rate(&usd, eur),
+            original_rate_buy(0.5, 8000., 1.)
+        ));
+
+        //after a purchase of 100 the market holds 7900
+        let mut bought = usd.clone();
+        bought.quantity = Fixed::from_f32(7900.);
+        pricing.update_rates(
+            &mut bought,
+            eur,
+            Fixed::from_f32(100.),
+            Fixed::from_f32(greediness),
+        );
+        let rate_buy = original_rate_buy(0.5, 8000., 100.);
+        assert!(close(bought.exchange_rate_buy, rate_buy));
+        assert!(close(
+            bought.exchange_rate_sell,
+            rate_buy / greediness as f64
+        ));
+
+        //after a sale of 100 the market holds 8100
+        let mut sold = usd.clone();
+        sold.quantity = Fixed::from_f32(8100.);
+        pricing.update_rates(
+            &mut sold,
+            eur,
+            Fixed::from_f32(-100.),
+            Fixed::from_f32(greediness),
+        );
+        let rate_buy = original_rate_buy(0.5, 8000., -100.);
+        assert!(close(sold.exchange_rate_buy, rate_buy));
+        assert!(close(sold.exchange_rate_sell, rate_buy / greediness as f64));
+    }
+
+    #[test]
+    fn constant_product_pricing_is_symmetric() {
+        use super::super::PricingMode;
+
+        let config = quiet_config()
+            .pricing_mode(PricingMode::ConstantProduct)
+            .build()
+            .unwrap();
+        let market = FskMarket::with_config(10000., 0., 10000., 0., config);
+        let mut market = market.borrow_mut();
+
+        let price = market.get_buy_price(GoodKind::USD, 100.).unwrap();
+        //buying moves along the curve: 100 units out of a 10000/10000 pool
+        assert!((price - 10000. * 100. / 9900.).abs() < 0.01);
+        let token = market
+            .lock_buy(GoodKind::USD, 100., price, "trader".to_string())
+            .unwrap();
+        market
+            .buy(token, &mut Good::new(GoodKind::EUR, price))
+            .unwrap();
+
+        //selling the same quantity back pays what was spent, minus the market greediness
+        let sell_price = market.get_sell_price(GoodKind::USD, 100.).unwrap();
+        let greediness = market.config().market_greediness();
+        assert!((sell_price * greediness - price).abs() < 0.01);
+    }
+
+    #[test]
+    fn sell_quote_applies_price_impact_and_matches_settlement() {
+        let config = quiet_config().build().unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+
+        let small = market.get_sell_price(GoodKind::USD, 1.).unwrap();
+        let large = market.get_sell_price(GoodKind::USD, 5000.).unwrap();
+        assert!(large / 5000. < small);
+
+        let token = market
+            .lock_sell(GoodKind::USD, 5000., large, "trader".to_string())
+            .unwrap();
+        market
+            .sell(token, &mut Good::new(GoodKind::USD, 5000.))
+            .unwrap();
+        let usd = market
+            .get_goods()
+            .into_iter()
+            .find(|good_label| good_label.good_kind == GoodKind::USD)
+            .unwrap();
+        assert!((usd.exchange_rate_sell * 5000. - large).abs() < 0.01);
+    }
+
+    #[test]
+    fn partial_lock_buy_reserves_what_the_market_can_supply() {
+        use super::super::LockError;
+        use unitn_market_2022::market::LockBuyError;
+
+        let config = quiet_config().build().unwrap();
+        let market = FskMarket::with_config(10000., 100., 0., 0., config);
+        let mut market = market.borrow_mut();
+
+        //the whole request can't be supplied, the bid is generous enough for most of the stock
+        let lock = market
+            .lock_buy_partial(GoodKind::YEN, 1000., 1000., "trader".to_string())
+            .unwrap();
+        assert!(lock.quantity > 0. && lock.quantity < 100.);
+        assert!((lock.price - lock.quantity).abs() < 0.001);
+        let yen = market
+            .get_goods()
+            .into_iter()
+            .find(|good_label| good_label.good_kind == GoodKind::YEN)
+            .unwrap();
+        assert!((yen.quantity - (100. - lock.quantity)).abs() < 0.001);
+
+        //the lock is settled like any other
+        let bought = market
+            .buy(lock.token, &mut Good::new(GoodKind::EUR, lock.price))
+            .unwrap();
+        assert_eq!(bought.get_qty(), lock.quantity);
+
+        //a bid too low for even part of the request names one that fills part of it
+        let refused = market.lock_buy_partial(GoodKind::YEN, 1000., 1., "trader".to_string());
+        let lowest_acceptable_bid = match refused {
+            Err(LockError::Market(LockBuyError::BidTooLow {
+                lowest_acceptable_bid,
+                ..
+            })) => lowest_acceptable_bid,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(lowest_acceptable_bid > 1. && lowest_acceptable_bid < f32::MAX);
+        let lock = market
+            .lock_buy_partial(
+                GoodKind::YEN,
+                1000.,
+                lowest_acceptable_bid * 1.001,
+                "trader".to_string(),
+            )
+            .unwrap();
+        assert!(lock.quantity > 0.);
+
+        assert!(matches!(
+            market.lock_buy_partial(GoodKind::YEN, 10., 0., "trader".to_string()),
+            Err(LockError::Market(LockBuyError::NonPositiveBid { .. }))
+        ));
+    }
+
+    #[test]
+    fn token_status_follows_cancellation_and_settlement() {
+        use super::super::{CancelLockError, LockSide, TokenStatus};
+
+        let config = quiet_config().build().unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        //locking is itself a tick, one of the 9 ticks of the lock is already gone
+        assert!(matches!(
+            market.token_status(&token),
+            TokenStatus::Active {
+                side: LockSide::Buy,
+                expires_in: 8,
+                ..
+            }
+        ));
+        assert_eq!(market.open_locks().len(), 1);
+
+        market.cancel_lock_buy(token.clone()).unwrap();
+        assert!(matches!(
+            market.token_status(&token),
+            TokenStatus::Cancelled
+        ));
+        assert!(market.open_locks().is_empty());
+        assert_eq!(
+            market.cancel_lock_buy(token.clone()),
+            Err(CancelLockError::AlreadyCancelled {
+                cancelled_token: token.clone()
+            })
+        );
+
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        market
+            .buy(token.clone(), &mut Good::new(GoodKind::EUR, price))
+            .unwrap();
+        assert!(matches!(market.token_status(&token), TokenStatus::Consumed));
+        assert_eq!(
+            market.cancel_lock_buy(token.clone()),
+            Err(CancelLockError::AlreadyConsumed {
+                consumed_token: token.clone()
+            })
+        );
+        assert!(matches!(
+            market.token_status("not a token"),
+            TokenStatus::Unknown
+        ));
+    }
+
+    #[test]
+    fn cancelled_locks_give_the_goods_back_and_are_audited() {
+        use super::super::{CancelLockError, LogFormat, LogRecord, MemoryLogSink, TokenStatus};
+        let log = MemoryLogSink::new();
+        //cancellations are only in JSON lines
+        let config = quiet_config()
+            .log_format(LogFormat::JsonLines)
+            .build()
+            .unwrap();
+        let last_entry = |log: &MemoryLogSink| {
+            let line = log.lines().pop().unwrap();
+            serde_json::from_str::<LogRecord>(&line)
+                .unwrap()
+                .legacy_entry()
+        };
+        let market = new_logged_market(config, Box::new(log.clone()));
+        let mut market = market.borrow_mut();
+        let events = Rc::new(RefCell::new(vec![]));
+        market.add_fsk_subscriber(Box::new(Recorder(events.clone())));
+        let quantity = |market: &FskMarket, kind| market.goods[&kind].quantity;
+
+        let usd = quantity(&market, GoodKind::USD);
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        market.cancel_lock_buy(token.clone()).unwrap();
+        assert_eq!(quantity(&market, GoodKind::USD), usd);
+        assert!(matches!(
+            market.token_status(&token),
+            TokenStatus::Cancelled
+        ));
+        assert_eq!(
+            last_entry(&log),
+            format!("CANCEL_LOCK_BUY-TOKEN:{}-OK", token)
+        );
+        assert!(matches!(
+            events.borrow().last(),
+            Some(FskEvent::LockBuyCancelled { token: cancelled, quantity, .. })
+                if *cancelled == token && *quantity == 10.
+        ));
+        assert_eq!(
+            market.cancel_lock_buy(token.clone()),
+            Err(CancelLockError::AlreadyCancelled {
+                cancelled_token: token
+            })
+        );
+
+        let eur = quantity(&market, GoodKind::EUR);
+        let offer = market.get_sell_price(GoodKind::YEN, 100.).unwrap();
+        let token = market
+            .lock_sell(GoodKind::YEN, 100., offer, "trader".to_string())
+            .unwrap();
+        market.cancel_lock_sell(token.clone()).unwrap();
+        assert_eq!(quantity(&market, GoodKind::EUR), eur);
+        assert!(matches!(
+            market.token_status(&token),
+            TokenStatus::Cancelled
+        ));
+        assert_eq!(
+            last_entry(&log),
+            format!("CANCEL_LOCK_SELL-TOKEN:{}-OK", token)
+        );
+        assert!(matches!(
+            events.borrow().last(),
+            Some(FskEvent::LockSellCancelled { token: cancelled, .. }) if *cancelled == token
+        ));
+        assert!(matches!(
+            market.cancel_lock_sell(token),
+            Err(CancelLockError::AlreadyCancelled { .. })
+        ));
+    }
+
+    #[test]
+    fn extended_locks_pay_the_fee_and_expire_at_their_new_tick() {
+        use super::super::{ExtendLockError, MarketConfig, NullLogSink, TokenStatus};
+        use unitn_market_2022::event::notifiable::Notifiable;
+
+        let config = MarketConfig::builder()
+            .lock_initial_ttl(3)
+            .max_lock_renewals(1)
+            .lock_renewal_fee(0.1)
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_logged_market(config, Box::new(NullLogSink));
+        let mut market = market.borrow_mut();
+
+        //the buyer pays the fee on top of the locked price
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        let extended = market.extend_lock_buy(token.clone()).unwrap();
+        assert!((extended.price - price * 1.1).abs() < 1e-3);
+        assert_eq!((extended.expires_in, extended.renewals), (5, 1));
+        assert_eq!(
+            market.extend_lock_buy(token.clone()),
+            Err(ExtendLockError::RenewalLimitReached {
+                token: token.clone(),
+                max_renewals: 1
+            })
+        );
+        market
+            .buy(token.clone(), &mut Good::new(GoodKind::EUR, price))
+            .unwrap_err();
+        market
+            .buy(token, &mut Good::new(GoodKind::EUR, extended.price))
+            .unwrap();
+
+        //the fee of a sell lock is kept out of the locked EUR and goes back to the market
+        let offer = market.get_sell_price(GoodKind::YEN, 100.).unwrap();
+        let token = market
+            .lock_sell(GoodKind::YEN, 100., offer, "trader".to_string())
+            .unwrap();
+        let budget = market.get_budget();
+        let extended = market.extend_lock_sell(token.clone()).unwrap();
+        assert!((extended.price - offer * 0.9).abs() < 1e-3);
+        assert!((market.get_budget() - (budget + offer * 0.1)).abs() < 1e-2);
+        let paid = market
+            .sell(token, &mut Good::new(GoodKind::YEN, 100.))
+            .unwrap();
+        assert!((paid.get_qty() - extended.price).abs() < 1e-3);
+
+        //the heap entry of the old expiry is stale, the lock only expires at the new one
+        let usd = |market: &FskMarket| {
+            market
+                .get_goods()
+                .into_iter()
+                .find(|label| label.good_kind == GoodKind::USD)
+                .unwrap()
+                .quantity
+        };
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        market.extend_lock_buy(token.clone()).unwrap();
+        let locked = usd(&market);
+        for _ in 0..2 {
+            market.on_event(wait());
+        }
+        assert!(matches!(
+            market.token_status(&token),
+            TokenStatus::Active { expires_in: 3, .. }
+        ));
+        assert_eq!(usd(&market), locked);
+        for _ in 0..3 {
+            market.on_event(wait());
+        }
+        assert!(matches!(market.token_status(&token), TokenStatus::Expired));
+        assert_eq!(usd(&market), locked + 10.);
+    }
+
+    #[test]
+    fn lock_quotas_stop_a_trader_from_cornering_a_good() {
+        use super::super::{LockError, QuotaError};
+        use unitn_market_2022::market::LockBuyError;
+
+        let config = quiet_config()
+            .max_locks_per_trader(2)
+            .max_locked_quantity_per_good(100.)
+            .build()
+            .unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+
+        let price = market.get_buy_price(GoodKind::YEN, 80.).unwrap();
+        market
+            .lock_buy(GoodKind::YEN, 80., price, "greedy".to_string())
+            .unwrap();
+        let price = market.get_buy_price(GoodKind::YEN, 30.).unwrap();
+        assert!(matches!(
+            market.try_lock_buy(GoodKind::YEN, 30., price, "greedy".to_string()),
+            Err(LockError::Quota(QuotaError::GoodQuantityLimit { .. }))
+        ));
+        //through the Market trait the trader learns how much is left of its allowance
+        assert!(matches!(
+            market.lock_buy(GoodKind::YEN, 30., price, "greedy".to_string()),
+            Err(LockBuyError::InsufficientGoodQuantityAvailable {
+                available_good_quantity,
+                ..
+            }) if (available_good_quantity - 20.).abs() < 0.001
+        ));
+        //other traders are not affected
+        market
+            .lock_buy(GoodKind::YEN, 30., price, "patient".to_string())
+            .unwrap();
+
+        let price = market.get_buy_price(GoodKind::USD, 1.).unwrap();
+        market
+            .lock_buy(GoodKind::USD, 1., price, "greedy".to_string())
+            .unwrap();
+        assert!(matches!(
+            market.try_lock_buy(GoodKind::USD, 1., price, "greedy".to_string()),
+            Err(LockError::Quota(QuotaError::TooManyLocks {
+                max_locks: 2,
+                ..
+            }))
+        ));
+        assert_eq!(market.open_locks_of("greedy").len(), 2);
+    }
+
+    #[test]
+    fn quota_errors_report_the_remaining_headroom() {
+        use unitn_market_2022::market::{LockBuyError, LockSellError};
+
+        let buy_headroom = |market: &mut FskMarket, kind, quantity, bid| match market.lock_buy(
+            kind,
+            quantity,
+            bid,
+            "trader".to_string(),
+        ) {
+            Err(LockBuyError::InsufficientGoodQuantityAvailable {
+                available_good_quantity,
+                ..
+            }) => available_good_quantity,
+            other => panic!("expected a quota error, got {:?}", other),
+        };
+        let sell_headroom = |market: &mut FskMarket, kind, quantity, offer| match market.lock_sell(
+            kind,
+            quantity,
+            offer,
+            "trader".to_string(),
+        ) {
+            Err(LockSellError::InsufficientDefaultGoodQuantityAvailable {
+                available_good_quantity,
+                ..
+            }) => available_good_quantity,
+            other => panic!("expected a quota error, got {:?}", other),
+        };
+
+        //no lock fits next to the ones already held
+        let config = quiet_config().max_locks_per_trader(1).build().unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        assert_eq!(buy_headroom(&mut market, GoodKind::USD, 10., price), 0.);
+        let offer = market.get_sell_price(GoodKind::YEN, 100.).unwrap();
+        assert_eq!(sell_headroom(&mut market, GoodKind::YEN, 100., offer), 0.);
+        drop(market);
+
+        //a buyer learns the quantity left, a seller what it is worth at its offer
+        let config = quiet_config()
+            .max_locked_quantity_per_good(100.)
+            .build()
+            .unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+        let offer = market.get_sell_price(GoodKind::YEN, 80.).unwrap();
+        market
+            .lock_sell(GoodKind::YEN, 80., offer, "trader".to_string())
+            .unwrap();
+        let price = market.get_buy_price(GoodKind::YEN, 30.).unwrap();
+        assert!((buy_headroom(&mut market, GoodKind::YEN, 30., price) - 20.).abs() < 1e-3);
+        let offer = market.get_sell_price(GoodKind::YEN, 30.).unwrap();
+        let available = sell_headroom(&mut market, GoodKind::YEN, 30., offer);
+        assert!((available - offer * 20. / 30.).abs() < 1e-3);
+        drop(market);
+
+        //a seller learns the EUR left, a buyer how much of the good they pay for at its bid
+        let config = quiet_config()
+            .max_locked_eur_per_trader(100.)
+            .build()
+            .unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+        let price = market.get_buy_price(GoodKind::USD, 50.).unwrap();
+        market
+            .lock_buy(GoodKind::USD, 50., price, "trader".to_string())
+            .unwrap();
+        let left = 100. - price;
+        let bid = market.get_buy_price(GoodKind::USD, 100.).unwrap();
+        let available = buy_headroom(&mut market, GoodKind::USD, 100., bid);
+        assert!((available - 100. * left / bid).abs() < 1e-3);
+        let offer = market.get_sell_price(GoodKind::USD, 100.).unwrap();
+        assert!((sell_headroom(&mut market, GoodKind::USD, 100., offer) - left).abs() < 1e-3);
+    }
+
+    #[test]
+    fn snapshots_keep_open_locks() {
+        use super::super::MarketSnapshot;
+
+        let dir = scratch_dir("snapshots_keep_open_locks");
+        let config = quiet_config().snapshot_dir(&dir).build().unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        market.take_snapshot("locks.json".to_string());
+
+        let json = std::fs::read_to_string(dir.join("locks.json")).unwrap();
+        let mut snapshot: MarketSnapshot = serde_json::from_str(&json).unwrap();
+        snapshot.buy_contracts_archive.rebuild_indexes();
+        let contract = &snapshot.buy_contracts_archive.contracts_by_token[&token];
+        assert_eq!(contract.quantity.to_f32(), 10.);
+        assert_eq!(contract.trader_name, "trader");
+        assert_eq!(snapshot.buy_contracts_archive.contracts_by_expiry.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restored_seeded_market_doesnt_reissue_tokens() {
+        use super::super::{MarketConfig, NullLogSink};
+
+        let dir = scratch_dir("restored_seeded_market");
+        let config = MarketConfig::builder()
+            .seed(7)
+            .snapshot_dir(&dir)
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_logged_market(config.clone(), Box::new(NullLogSink));
+        let price = market.borrow().get_buy_price(GoodKind::USD, 10.).unwrap();
+        let cancelled = market
+            .borrow_mut()
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        market
+            .borrow_mut()
+            .cancel_lock_buy(cancelled.clone())
+            .unwrap();
+        market.borrow().take_snapshot("seeded.json".to_string());
+        drop(market);
+
+        let market = FskMarket::try_from_snapshot_with_log_sink(
+            dir.join("seeded.json"),
+            config,
+            Box::new(NullLogSink),
+        )
+        .unwrap();
+        let token = market
+            .borrow_mut()
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        assert_ne!(token, cancelled);
+        market.borrow_mut().cancel_lock_buy(token.clone()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        //the restored market draws the token the market would have drawn without the restart
+        let config = MarketConfig::builder()
+            .seed(7)
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_logged_market(config, Box::new(NullLogSink));
+        let mut market = market.borrow_mut();
+        let first = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        market.cancel_lock_buy(first).unwrap();
+        let second = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        assert_eq!(second, token);
+    }
+
+    #[test]
+    fn restored_market_honours_tokens_issued_before_the_restart() {
+        use super::super::SnapshotError;
+
+        let dir = scratch_dir("restored_market_honours_tokens");
+        let config = quiet_config().snapshot_dir(&dir).build().unwrap();
+        let market = new_market(config.clone());
+        let price = market.borrow().get_buy_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .borrow_mut()
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        market.borrow().take_snapshot("restart.json".to_string());
+        drop(market);
+
+        let market =
+            FskMarket::try_from_snapshot_with_config(dir.join("restart.json"), config.clone())
+                .unwrap();
+        let bought = market
+            .borrow_mut()
+            .buy(token, &mut Good::new(GoodKind::EUR, price))
+            .unwrap();
+        assert_eq!(bought.get_qty(), 10.);
+
+        assert!(matches!(
+            FskMarket::try_from_snapshot_with_config(dir.join("missing.json"), config.clone()),
+            Err(SnapshotError::Io { .. })
+        ));
+        std::fs::write(dir.join("garbage.json"), "not a snapshot").unwrap();
+        assert!(matches!(
+            FskMarket::try_from_snapshot_with_config(dir.join("garbage.json"), config.clone()),
+            Err(SnapshotError::Parse { .. })
+        ));
+        std::fs::write(
+            dir.join("no_goods.json"),
+            r#"{"goods": {}, "time": 3, "last_trader_interaction": 1}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            FskMarket::try_from_snapshot_with_config(dir.join("no_goods.json"), config),
+            Err(SnapshotError::Invalid { .. })
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn restore_fixture(
+        name: &str,
+    ) -> Result<std::rc::Rc<std::cell::RefCell<FskMarket>>, super::super::SnapshotError> {
+        restore_fixture_with(name, quiet_config())
+    }
+
+    fn restore_fixture_with(
+        name: &str,
+        config: super::super::MarketConfigBuilder,
+    ) -> Result<std::rc::Rc<std::cell::RefCell<FskMarket>>, super::super::SnapshotError> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/snapshots")
+            .join(name);
+        let dir = scratch_dir(&format!(
+            "snapshot_fixture_{}",
+            name.trim_end_matches(".json")
+        ));
+        let config = config.snapshot_dir(&dir).build().unwrap();
+        let market = FskMarket::try_from_snapshot_with_config(path, config);
+        //restoring takes a first snapshot, the tests don't write any other
+        let _ = std::fs::remove_dir_all(&dir);
+        market
+    }
+
+    #[test]
+    fn snapshot_v1_fixture_is_migrated() {
+        use super::super::TokenStatus;
+
+        let market = restore_fixture("v1.json").unwrap();
+        let market = market.borrow();
+        assert_eq!(market.get_budget(), 9500.);
+        assert!(market.open_locks().is_empty());
+        assert!(matches!(
+            market.token_status("k3x9q0ab7d"),
+            TokenStatus::Unknown
+        ));
+    }
+
+    #[test]
+    fn snapshot_v2_fixture_keeps_locks_and_retired_tokens() {
+        use super::super::TokenStatus;
+
+        let market = restore_fixture("v2.json").unwrap();
+        let mut market = market.borrow_mut();
+        assert!(matches!(
+            market.token_status("0c1t5w2mzz"),
+            TokenStatus::Expired
+        ));
+        assert!(matches!(
+            market.token_status("q8r7s6t5u4"),
+            TokenStatus::Consumed
+        ));
+        let bought = market
+            .buy("k3x9q0ab7d".to_string(), &mut Good::new(GoodKind::EUR, 97.))
+            .unwrap();
+        assert_eq!(bought.get_qty(), 100.);
+    }
+
+    #[test]
+    fn snapshot_v3_fixture_keeps_locks_and_retirement_ticks() {
+        use super::super::{LockSide, TokenStatus};
+        use unitn_market_2022::event::notifiable::Notifiable;
+
+        let market = restore_fixture("v3.json").unwrap();
+        let mut market = market.borrow_mut();
+        assert_eq!(market.get_budget(), 9400.5);
+        //migrated from the decimal in the file, not from the nearest f32
+        assert_eq!(
+            market.goods[&GoodKind::EUR].exchange_rate_sell,
+            "0.990099".parse().unwrap()
+        );
+        let locks = market.open_locks();
+        assert_eq!(locks.len(), 2);
+        assert_eq!(
+            (locks[0].side, locks[0].token.as_str(), locks[0].expires_in),
+            (LockSide::Buy, "k3x9q0ab7d", 6)
+        );
+        assert_eq!((locks[0].quantity, locks[0].price), (100., 97.));
+        assert_eq!(
+            (
+                locks[1].side,
+                locks[1].trader_name.as_str(),
+                locks[1].expires_in
+            ),
+            (LockSide::Sell, "seller", 3)
+        );
+        for (token, expired, consumed, cancelled) in [
+            ("0c1t5w2mzz", true, false, false),
+            ("h2j4k6m8n0", false, true, false),
+            ("p1q2r3s4t5", false, false, true),
+            ("q8r7s6t5u4", false, true, false),
+        ] {
+            let status = market.token_status(token);
+            assert_eq!(matches!(status, TokenStatus::Expired), expired, "{}", token);
+            assert_eq!(
+                matches!(status, TokenStatus::Consumed),
+                consumed,
+                "{}",
+                token
+            );
+            assert_eq!(
+                matches!(status, TokenStatus::Cancelled),
+                cancelled,
+                "{}",
+                token
+            );
+        }
+        let paid = market
+            .sell(
+                "s9t8u7v6w5".to_string(),
+                &mut Good::new(GoodKind::YEN, 10000.),
+            )
+            .unwrap();
+        assert_eq!(paid.get_qty(), 74.25);
+
+        //tokens are forgotten counting from the tick they were retired at, not from the restore
+        let market = restore_fixture_with("v3.json", quiet_config().retired_token_ttl(4)).unwrap();
+        let mut market = market.borrow_mut();
+        market.on_event(wait());
+        assert!(matches!(
+            market.token_status("p1q2r3s4t5"),
+            TokenStatus::Unknown
+        ));
+        assert!(matches!(
+            market.token_status("h2j4k6m8n0"),
+            TokenStatus::Unknown
+        ));
+        assert!(matches!(
+            market.token_status("q8r7s6t5u4"),
+            TokenStatus::Consumed
+        ));
+        assert!(matches!(
+            market.token_status("0c1t5w2mzz"),
+            TokenStatus::Expired
+        ));
+    }
+
+    #[test]
+    fn snapshot_v4_fixture_keeps_exact_decimals() {
+        use super::super::{Fixed, TokenStatus};
+
+        let exact = |value: &str| value.parse::<Fixed>().unwrap();
+        let market = restore_fixture("v4.json").unwrap();
+        let mut market = market.borrow_mut();
+        assert_eq!(
+            market.goods[&GoodKind::EUR].quantity,
+            exact("9400.123456789")
+        );
+        assert_eq!(
+            market.goods[&GoodKind::YEN].exchange_rate_buy,
+            exact("0.007512345")
+        );
+        let contract = &market.buy_contracts_archive.contracts_by_token["k3x9q0ab7d"];
+        assert_eq!(contract.kind, GoodKind::USD);
+        assert_eq!(
+            (contract.quantity, contract.price),
+            (exact("100.5"), exact("97.123456789"))
+        );
+        let contract = &market.sell_contracts_archive.contracts_by_token["s9t8u7v6w5"];
+        assert_eq!(contract.kind, GoodKind::YEN);
+        assert_eq!(
+            (contract.quantity, contract.price),
+            (exact("10000.25"), exact("74.000000005"))
+        );
+        assert!(matches!(
+            market.token_status("p1q2r3s4t5"),
+            TokenStatus::Cancelled
+        ));
+
+        //settling adds the exact locked price to the reserve
+        let bought = market
+            .buy(
+                "k3x9q0ab7d".to_string(),
+                &mut Good::new(GoodKind::EUR, exact("97.123456789").to_f32()),
+            )
+            .unwrap();
+        assert_eq!(bought.get_qty(), 100.5);
+        assert_eq!(
+            market.goods[&GoodKind::EUR].quantity,
+            exact("9497.246913578")
+        );
+    }
+
+    #[test]
+    fn snapshot_from_a_newer_market_is_rejected() {
+        use super::super::{SnapshotError, SNAPSHOT_VERSION};
+
+        let dir = scratch_dir("snapshot_from_the_future");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("future.json");
+        std::fs::write(&path, format!(r#"{{"version": {}}}"#, SNAPSHOT_VERSION + 1)).unwrap();
+        let config = quiet_config().build().unwrap();
+        assert_eq!(
+            FskMarket::try_from_snapshot_with_config(path, config).err(),
+            Some(SnapshotError::UnsupportedVersion {
+                version: SNAPSHOT_VERSION + 1,
+                latest: SNAPSHOT_VERSION,
+            })
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_retention_keeps_recent_and_milestone_ticks() {
+        use super::super::{ConfigError, MarketConfig};
+
+        let dir = scratch_dir("snapshot_retention");
+        let config = quiet_config()
+            .snapshot_dir(&dir)
+            .market_name("alpha")
+            .snapshot_keep_last(2)
+            .snapshot_keep_every(4)
+            .build()
+            .unwrap();
+        let market = new_market(config);
+        for tick in 1..=6 {
+            market.borrow_mut().time = tick;
+            market.borrow().take_snapshot(String::new());
+        }
+        drop(market);
+
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "market_alpha_snapshot_4.json",
+                "market_alpha_snapshot_5.json",
+                "market_alpha_snapshot_6.json",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(
+            MarketConfig::builder()
+                .snapshot_name_template("../{market}_{day}.json")
+                .build(),
+            Err(ConfigError::OutOfRange {
+                field: "snapshot_name_template",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn snapshots_follow_the_schedule() {
+        use unitn_market_2022::event::notifiable::Notifiable;
+
+        let snapshot_names = |dir: &std::path::Path| {
+            let mut names: Vec<String> = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let dir = scratch_dir("snapshot_schedule");
+        let config = quiet_config()
+            .snapshot_dir(&dir)
+            .market_name("beta")
+            .snapshot_every_ticks(3)
+            .snapshot_on_trade(true)
+            .build()
+            .unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+        //tick 1
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        //tick 2, a trade
+        market
+            .buy(token, &mut Good::new(GoodKind::EUR, price))
+            .unwrap();
+        //tick 3, a multiple of 3
+        market.on_event(wait());
+        assert_eq!(
+            snapshot_names(&dir),
+            vec!["market_beta_snapshot_2.json", "market_beta_snapshot_3.json"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        //black friday starts at tick 4 and moves every price by 20%
+        let dir = scratch_dir("snapshot_on_price_move");
+        let config = quiet_config()
+            .snapshot_dir(&dir)
+            .market_name("gamma")
+            .snapshot_on_price_move(0.1)
+            .build()
+            .unwrap();
+        let market = new_market(config);
+        for _ in 0..4 {
+            market.borrow_mut().on_event(wait());
+        }
+        assert_eq!(snapshot_names(&dir), vec!["market_gamma_snapshot_4.json"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_lines_log_has_typed_fields() {
+        use super::super::{LogFormat, LogOp, LogOutcome, LogRecord, MarketConfig, MemoryLogSink};
+
+        let log = MemoryLogSink::new();
+        let config = MarketConfig::builder()
+            .log_format(LogFormat::JsonLines)
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_logged_market(config, Box::new(log.clone()));
+        let mut market = market.borrow_mut();
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        market
+            .lock_buy(GoodKind::USD, 10., price / 2., "jean-luc".to_string())
+            .unwrap_err();
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "jean-luc".to_string())
+            .unwrap();
+
+        let records: Vec<LogRecord> = log
+            .lines()
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].op, LogOp::MarketInitialization);
+        assert_eq!(records[1].trader.as_deref(), Some("jean-luc"));
+        assert_eq!(records[1].outcome, LogOutcome::Error);
+        assert_eq!(records[1].error.as_deref(), Some("BidTooLow"));
+        assert_eq!(records[2].kind, Some(GoodKind::USD));
+        assert_eq!(records[2].qty, Some(10.));
+        assert_eq!(records[2].token, Some(token.clone()));
+        assert_eq!(records[2].tick, 0);
+
+        //the legacy format is unchanged
+        assert_eq!(
+            records[2].legacy_entry(),
+            format!(
+                "LOCK_BUY-jean-luc-KIND_TO_BUY:USD-QUANTITY_TO_BUY:{:+e}-BID:{:+e}-TOKEN:{}",
+                10., price, token
+            )
+        );
+    }
+
+    #[test]
+    fn memory_log_sink_records_the_lines_of_each_call() {
+        use super::super::{MarketConfig, MemoryLogSink};
+
+        let log = MemoryLogSink::new();
+        let config = MarketConfig::builder()
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_logged_market(config, Box::new(log.clone()));
+        let mut market = market.borrow_mut();
+        let init = log.take();
+        assert_eq!(init.len(), 1);
+        assert!(init[0].starts_with("FSK|"));
+        assert!(init[0].ends_with("\nEND_MARKET_INITIALIZATION"));
+
+        let token = market
+            .lock_sell(GoodKind::YEN, 10., 0.01, "trader".to_string())
+            .unwrap();
+        market
+            .sell(token.clone(), &mut Good::new(GoodKind::USD, 10.))
+            .unwrap_err();
+        market
+            .sell(token.clone(), &mut Good::new(GoodKind::YEN, 10.))
+            .unwrap();
+        let entries: Vec<String> = log
+            .take()
+            .iter()
+            //drop the market name and the timestamp
+            .map(|line| line.splitn(3, '|').nth(2).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                format!(
+                    "LOCK_SELL-trader-KIND_TO_SELL:YEN-QUANTITY_TO_SELL:{:+e}-OFFER:{:+e}-TOKEN:{}",
+                    10., 0.01, token
+                ),
+                format!("SELL-TOKEN:{}-ERROR", token),
+                format!("SELL-TOKEN:{}-OK", token),
+            ]
+        );
+    }
+
+    #[test]
+    fn log_rotates_by_size_and_ticks() {
+        use super::super::MarketConfig;
+        use unitn_market_2022::event::notifiable::Notifiable;
+
+        let dir = scratch_dir("log_rotation");
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = MarketConfig::builder()
+            .log_path(dir.join("log.txt"))
+            .log_max_bytes(600)
+            .log_keep_rotated(2)
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_market(config);
+        for _ in 0..20 {
+            market
+                .borrow_mut()
+                .lock_buy(GoodKind::USD, 10., 0.01, "trader".to_string())
+                .unwrap_err();
+        }
+        for name in ["log.txt", "log.txt.1", "log.txt.2"] {
+            let content = std::fs::read_to_string(dir.join(name)).unwrap();
+            assert!(content.len() <= 600);
+            //every file starts with the market state
+            assert_eq!(content.lines().nth(1), Some("MARKET_INITIALIZATION"));
+        }
+        assert!(!dir.join("log.txt.3").exists());
+
+        let config = MarketConfig::builder()
+            .log_path(dir.join("ticks.txt"))
+            .log_rotate_every_ticks(2)
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+        market.on_event(wait());
+        market
+            .lock_buy(GoodKind::USD, 10., 0.01, "trader".to_string())
+            .unwrap_err();
+        assert!(!dir.join("ticks.txt.1").exists());
+        market.on_event(wait());
+        market
+            .lock_buy(GoodKind::USD, 10., 0.01, "trader".to_string())
+            .unwrap_err();
+        assert!(dir.join("ticks.txt.1").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_log_only_has_the_baseline_operations() {
+        use super::super::{MarketConfig, MemoryLogSink};
+        use unitn_market_2022::event::notifiable::Notifiable;
+
+        let log = MemoryLogSink::new();
+        let config = MarketConfig::builder()
+            .lock_initial_ttl(2)
+            .trader_inactivity_time(1)
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_logged_market(config, Box::new(log.clone()));
+        let mut market = market.borrow_mut();
+        let offer = market.get_sell_price(GoodKind::YEN, 10.).unwrap();
+        let token = market
+            .lock_sell(GoodKind::YEN, 10., offer, "trader".to_string())
+            .unwrap();
+        market.cancel_lock_sell(token).unwrap();
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        market.extend_lock_buy(token).unwrap();
+        //the lock expires, rates decay and black friday starts and ends, the checker knows none of it
+        for _ in 0..7 {
+            market.on_event(wait());
+        }
+
+        let operations: Vec<String> = log
+            .lines()
+            .iter()
+            .map(|line| {
+                let entry = line.splitn(3, '|').nth(2).unwrap();
+                let entry = entry.trim_start_matches('\n');
+                entry.split(['-', '\n']).next().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(
+            operations,
+            vec!["MARKET_INITIALIZATION", "LOCK_SELL", "LOCK_BUY"]
+        );
+    }
+
+    #[test]
+    fn expiries_black_friday_and_decay_are_audited() {
+        use super::super::{LogFormat, LogRecord, MarketConfig, MemoryLogSink};
+        use unitn_market_2022::event::notifiable::Notifiable;
+
+        let log = MemoryLogSink::new();
+        let config = MarketConfig::builder()
+            .lock_initial_ttl(2)
+            .trader_inactivity_time(3)
+            .log_format(LogFormat::JsonLines)
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_logged_market(config, Box::new(log.clone()));
+        let mut market = market.borrow_mut();
+        let events = Rc::new(RefCell::new(vec![]));
+        market.add_fsk_subscriber(Box::new(Recorder(events.clone())));
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        log.take();
+        //the lock ticked the market to 1 and expires at 3, rates decay from 4 as locking isn't a
+        //trade, black friday runs from 4 to 5
+        for _ in 0..4 {
+            market.on_event(wait());
+        }
+
+        let entries: Vec<String> = log
+            .take()
+            .iter()
+            .map(|line| {
+                serde_json::from_str::<LogRecord>(line)
+                    .unwrap()
+                    .legacy_entry()
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                format!(
+                    "LOCK_BUY_EXPIRED-TOKEN:{}-KIND:USD-QUANTITY:{:+e}-PRICE:{:+e}",
+                    token, 10., price
+                ),
+                format!("INACTIVITY_DECAY-FACTOR:{:+e}", 0.999f32),
+                format!("BLACK_FRIDAY_START-FACTOR:{:+e}", 1. - 0.2f32),
+                format!("INACTIVITY_DECAY-FACTOR:{:+e}", 0.999f32),
+                format!("BLACK_FRIDAY_END-FACTOR:{:+e}", 1. / (1. - 0.2f32)),
+            ]
+        );
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                FskEvent::LockBuyExpired {
+                    token,
+                    good_kind: GoodKind::USD,
+                    quantity: 10.,
+                    price,
+                },
+                FskEvent::RatesDecayed {
+                    factor: 0.999,
+                    inactive_ticks: 4,
+                },
+                FskEvent::BlackFridayStarted { discount: 0.2 },
+                FskEvent::RatesDecayed {
+                    factor: 0.999,
+                    inactive_ticks: 5,
+                },
+                FskEvent::BlackFridayEnded { discount: 0.2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn seeded_markets_are_reproducible() {
+        use super::super::{LogFormat, MarketConfig, MemoryLogSink};
+
+        let run = |seed: u64| {
+            let log = MemoryLogSink::new();
+            let config = MarketConfig::builder()
+                .seed(seed)
+                .log_format(LogFormat::JsonLines)
+                .check_invariants(true)
+                .snapshot_on_drop(false)
+                .build()
+                .unwrap();
+            let market = new_logged_market(config, Box::new(log.clone()));
+            let mut market = market.borrow_mut();
+            let mut tokens = vec![];
+            for kind in [GoodKind::USD, GoodKind::YEN, GoodKind::YUAN] {
+                let price = market.get_buy_price(kind, 10.).unwrap();
+                tokens.push(
+                    market
+                        .lock_buy(kind, 10., price, "trader".to_string())
+                        .unwrap(),
+                );
+                let offer = market.get_sell_price(kind, 10.).unwrap();
+                tokens.push(
+                    market
+                        .lock_sell(kind, 10., offer, "trader".to_string())
+                        .unwrap(),
+                );
+            }
+            (tokens, log.lines())
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42).0, run(43).0);
+
+        let quantities = |market: &FskMarket| {
+            let goods = market.get_goods();
+            [GoodKind::EUR, GoodKind::YEN, GoodKind::USD, GoodKind::YUAN].map(|kind| {
+                goods
+                    .iter()
+                    .find(|good_label| good_label.good_kind == kind)
+                    .unwrap()
+                    .quantity
+            })
+        };
+        let config = quiet_config().seed(42).build().unwrap();
+        let first = FskMarket::new_random_with_config(config.clone());
+        let second = FskMarket::new_random_with_config(config);
+        assert_eq!(quantities(&first.borrow()), quantities(&second.borrow()));
+
+        //legacy lines keep the wall-clock time the log checker expects, YY:MM:DD:HH:MM:SEC:MSES
+        let log = MemoryLogSink::new();
+        let _market = new_logged_market(
+            quiet_config().seed(42).build().unwrap(),
+            Box::new(log.clone()),
+        );
+        let line = log.lines().remove(0);
+        let timestamp = line.split('|').nth(1).unwrap();
+        assert_eq!(timestamp.split(':').count(), 7);
+    }
+
+    #[test]
+    fn trader_bound_tokens_are_settled_only_by_their_trader() {
+        use super::super::{
+            replay_log, LogFormat, MemoryLogSink, SettleError, TokenMode, TokenStatus,
+        };
+        use unitn_market_2022::market::{BuyError, SellError};
+
+        //legacy lines don't say why an operation failed
+        let log = MemoryLogSink::new();
+        let config = quiet_config()
+            .log_format(LogFormat::JsonLines)
+            .token_mode(TokenMode::TraderBound)
+            .build()
+            .unwrap();
+        let market = new_logged_market(config.clone(), Box::new(log.clone()));
+        let mut market = market.borrow_mut();
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "alice".to_string())
+            .unwrap();
+        assert_eq!(token.len(), 32);
+
+        let mut cash = Good::new(GoodKind::EUR, price);
+        let stolen = market.buy_as("mallory".to_string(), token.clone(), &mut cash);
+        assert!(matches!(
+            stolen,
+            Err(SettleError::WrongTrader { trader_name, .. }) if trader_name == "mallory"
+        ));
+        //buy doesn't know who is settling, the token is refused and the lock stays open
+        assert!(matches!(
+            market.buy(token.clone(), &mut cash),
+            Err(BuyError::UnrecognizedToken { .. })
+        ));
+        assert!(log.lines().last().unwrap().contains("TraderBoundToken"));
+        assert!(matches!(
+            market.token_status(&token),
+            TokenStatus::Active { .. }
+        ));
+        assert!(matches!(
+            market.buy("0".repeat(32), &mut cash),
+            Err(BuyError::UnrecognizedToken { .. })
+        ));
+        let bought = market
+            .buy_as("alice".to_string(), token, &mut cash)
+            .unwrap();
+        assert_eq!(bought.get_qty(), 10.);
+
+        let offer = market.get_sell_price(GoodKind::YEN, 10.).unwrap();
+        let token = market
+            .lock_sell(GoodKind::YEN, 10., offer, "alice".to_string())
+            .unwrap();
+        let mut yen = Good::new(GoodKind::YEN, 10.);
+        assert!(matches!(
+            market.sell_as("mallory".to_string(), token.clone(), &mut yen),
+            Err(SettleError::WrongTrader { .. })
+        ));
+        assert!(matches!(
+            market.sell(token.clone(), &mut yen),
+            Err(SellError::UnrecognizedToken { .. })
+        ));
+        market
+            .sell_as("alice".to_string(), token, &mut yen)
+            .unwrap();
+
+        //the replay tells the refusals of buy and sell from real expiries
+        let report = replay_log(&log.lines().join("\n"), config).unwrap();
+        assert_eq!(report.divergence, None);
+    }
+
+    #[test]
+    fn retired_tokens_are_forgotten_after_their_ttl() {
+        use super::super::TokenStatus;
+        use unitn_market_2022::event::notifiable::Notifiable;
+        use unitn_market_2022::market::BuyError;
+
+        let config = quiet_config()
+            .lock_initial_ttl(1)
+            .retired_token_ttl(3)
+            .build()
+            .unwrap();
+        let market = new_market(config);
+        let mut market = market.borrow_mut();
+        let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+        //locked at 0, the notification ticks the market to 1 and the lock expires right away
+        let token = market
+            .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+            .unwrap();
+        assert!(matches!(market.token_status(&token), TokenStatus::Expired));
+
+        //still remembered at the last tick of the window
+        market.on_event(wait());
+        market.on_event(wait());
+        assert!(matches!(
+            market.buy(token.clone(), &mut Good::new(GoodKind::EUR, price)),
+            Err(BuyError::ExpiredToken { .. })
+        ));
+
+        market.on_event(wait());
+        assert!(matches!(market.token_status(&token), TokenStatus::Unknown));
+        assert!(matches!(
+            market.buy(token, &mut Good::new(GoodKind::EUR, price)),
+            Err(BuyError::UnrecognizedToken { .. })
+        ));
+    }
+
+    #[test]
+    fn buy_sell_round_trips_conserve_value_exactly() {
+        use super::super::Fixed;
+
+        //an f32 reserve of 10 million yen can't tell 0.1 yen apart
+        let config = quiet_config().build().unwrap();
+        let market = FskMarket::with_config(100000., 10000000., 0., 0., config);
+        let mut market = market.borrow_mut();
+        let reserves = |market: &FskMarket| {
+            (
+                market.goods[&GoodKind::EUR].quantity,
+                market.goods[&GoodKind::YEN].quantity,
+            )
+        };
+        let (eur, yen) = reserves(&market);
+
+        let mut paid = Fixed::ZERO;
+        let mut received = Fixed::ZERO;
+        for _ in 0..10 {
+            let price = market.get_buy_price(GoodKind::YEN, 0.1).unwrap();
+            let token = market
+                .lock_buy(GoodKind::YEN, 0.1, price, "trader".to_string())
+                .unwrap();
+            market
+                .buy(token, &mut Good::new(GoodKind::EUR, price))
+                .unwrap();
+            paid += Fixed::from_f32(price);
+
+            let offer = market.get_sell_price(GoodKind::YEN, 0.1).unwrap();
+            let token = market
+                .lock_sell(GoodKind::YEN, 0.1, offer, "trader".to_string())
+                .unwrap();
+            market
+                .sell(token, &mut Good::new(GoodKind::YEN, 0.1))
+                .unwrap();
+            received += Fixed::from_f32(offer);
+        }
+        let (eur_after, yen_after) = reserves(&market);
+        assert_eq!(yen_after, yen);
+        assert_eq!(eur_after, eur + paid - received);
+
+        //snapshots store the exact decimal
+        assert_eq!(Fixed::from_f32(-12.5).to_string(), "-12.5");
+        for value in [Fixed::MIN, Fixed::MAX, Fixed::from_f32(-0.000000001)] {
+            assert_eq!(value.to_string().parse::<Fixed>(), Ok(value));
+        }
+        assert_eq!("0.0075".parse::<Fixed>(), Ok(Fixed::from_f32(0.0075)));
+        assert!("0.0000000001".parse::<Fixed>().is_err());
+    }
+
+    #[test]
+    fn buy_sell_cycles_bring_the_rates_back() {
+        //black friday would move the rates on its own
+        let config = quiet_config()
+            .black_friday_period(1000000)
+            .black_friday_start(999999)
+            .build()
+            .unwrap();
+        let market = FskMarket::with_config(100000., 10000000., 0., 0., config);
+        let mut market = market.borrow_mut();
+        let rates = |market: &FskMarket| {
+            let yen = &market.goods[&GoodKind::YEN];
+            (yen.exchange_rate_buy, yen.exchange_rate_sell)
+        };
+        let (buy_rate, sell_rate) = rates(&market);
+
+        for _ in 0..500 {
+            let price = market.get_buy_price(GoodKind::YEN, 1234.5).unwrap();
+            let token = market
+                .lock_buy(GoodKind::YEN, 1234.5, price, "trader".to_string())
+                .unwrap();
+            market
+                .buy(token, &mut Good::new(GoodKind::EUR, price))
+                .unwrap();
+            let offer = market.get_sell_price(GoodKind::YEN, 1234.5).unwrap();
+            let token = market
+                .lock_sell(GoodKind::YEN, 1234.5, offer, "trader".to_string())
+                .unwrap();
+            market
+                .sell(token, &mut Good::new(GoodKind::YEN, 1234.5))
+                .unwrap();
+        }
+        let (buy_rate_after, sell_rate_after) = rates(&market);
+        assert_eq!(buy_rate_after, buy_rate);
+        assert_eq!(sell_rate_after, sell_rate);
+    }
+
+    #[test]
+    #[should_panic(expected = "EUR inventory and locks add up to")]
+    fn invariant_checker_catches_value_created_on_lock_expiry() {
+        use super::super::{MarketConfig, NullLogSink};
+        use unitn_market_2022::event::notifiable::Notifiable;
+
+        let config = MarketConfig::builder()
+            .check_invariants(true)
+            .snapshot_on_drop(false)
+            .build()
+            .unwrap();
+        let market = new_logged_market(config, Box::new(NullLogSink));
+        let mut market = market.borrow_mut();
+        let offer = market.get_sell_price(GoodKind::USD, 10.).unwrap();
+        let token = market
+            .lock_sell(GoodKind::USD, 10., offer, "trader".to_string())
+            .unwrap();
+
+        //release the lock crediting the sold good instead of EUR
+        let contract = market
+            .sell_contracts_archive
+            .contracts_by_token
+            .remove(&token)
+            .unwrap();
+        market.goods.get_mut(&GoodKind::USD).unwrap().quantity += contract.price;
+        market.on_event(wait());
+    }
+
+    #[test]
+    fn replay_reproduces_the_log_and_finds_divergences() {
+        use super::super::{replay_log, LogFormat, MarketConfig, MemoryLogSink};
+
+        for format in [LogFormat::Legacy, LogFormat::JsonLines] {
+            let log = MemoryLogSink::new();
+            let config = MarketConfig::builder()
+                .log_format(format)
+                .check_invariants(true)
+                .snapshot_on_drop(false)
+                .build()
+                .unwrap();
+            let market = new_logged_market(config, Box::new(log.clone()));
+            let mut market = market.borrow_mut();
+            let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+            market
+                .lock_buy(GoodKind::USD, 10., price / 2., "trader".to_string())
+                .unwrap_err();
+            let token = market
+                .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+                .unwrap();
+            market
+                .buy(token.clone(), &mut Good::new(GoodKind::EUR, price))
+                .unwrap();
+            let offer = market.get_sell_price(GoodKind::YEN, 100.).unwrap();
+            let token = market
+                .lock_sell(GoodKind::YEN, 100., offer, "trader".to_string())
+                .unwrap();
+            //the trader pays with the wrong good
+            market
+                .sell(token.clone(), &mut Good::new(GoodKind::USD, 100.))
+                .unwrap_err();
+            market.cancel_lock_sell(token).unwrap();
+
+            let lines = log.lines();
+            let report = replay_log(&lines.join("\n"), quiet_config().build().unwrap()).unwrap();
+            assert_eq!(report.divergence, None);
+            //the legacy log has no cancellation
+            let entries = match format {
+                LogFormat::Legacy => 6,
+                LogFormat::JsonLines => 7,
+            };
+            assert_eq!(report.replayed, entries);
+
+            //a log claiming the first lock went through doesn't match the market
+            let mut tampered = lines.clone();
+            tampered[1] = tampered[1]
+                .replace("-ERROR", "-TOKEN:0000000000")
+                .replace("\"error\"", "\"ok\"")
+                .replace("\"BidTooLow\"", "null");
+            let report = replay_log(&tampered.join("\n"), quiet_config().build().unwrap()).unwrap();
+            let divergence = report.divergence.unwrap();
+            assert_eq!(report.replayed, 1);
+            assert_eq!(
+                divergence.message,
+                "the log says OK, the replayed market failed with BidTooLow"
+            );
+        }
+    }
+
+    #[test]
+    fn replay_continues_across_a_rotated_log() {
+        use super::super::{replay_file, replay_log, LogFormat, MarketConfig};
+        use unitn_market_2022::event::notifiable::Notifiable;
+
+        let dir = scratch_dir("replay_rotation");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (format, name) in [
+            (LogFormat::Legacy, "legacy.txt"),
+            (LogFormat::JsonLines, "json.txt"),
+        ] {
+            let config = MarketConfig::builder()
+                .log_path(dir.join(name))
+                .log_format(format)
+                .log_rotate_every_ticks(2)
+                .check_invariants(true)
+                .snapshot_on_drop(false)
+                .build()
+                .unwrap();
+            let market = new_market(config);
+            let mut market = market.borrow_mut();
+            let price = market.get_buy_price(GoodKind::USD, 10.).unwrap();
+            let buy_token = market
+                .lock_buy(GoodKind::USD, 10., price, "trader".to_string())
+                .unwrap();
+            let offer = market.get_sell_price(GoodKind::YEN, 100.).unwrap();
+            let sell_token = market
+                .lock_sell(GoodKind::YEN, 100., offer, "trader".to_string())
+                .unwrap();
+            market.on_event(wait());
+            //both locks are still open when the log is rotated
+            let rotated = dir.join(format!("{}.1", name));
+            assert!(rotated.exists());
+            market
+                .buy(buy_token, &mut Good::new(GoodKind::EUR, price))
+                .unwrap();
+            //the settlement ticks the market to 4, the current file starts there
+            let quantity = |kind: GoodKind| {
+                let good = market
+                    .get_goods()
+                    .into_iter()
+                    .find(|good| good.good_kind == kind);
+                format!("{}: {:+e}", kind, good.unwrap().quantity)
+            };
+            let baseline_header = [
+                "MARKET_INITIALIZATION".to_string(),
+                quantity(GoodKind::EUR),
+                quantity(GoodKind::USD),
+                quantity(GoodKind::YEN),
+                quantity(GoodKind::YUAN),
+                "END_MARKET_INITIALIZATION".to_string(),
+            ];
+            market
+                .sell(sell_token, &mut Good::new(GoodKind::YEN, 100.))
+                .unwrap();
+
+            let current = std::fs::read_to_string(dir.join(name)).unwrap();
+            if format == LogFormat::Legacy {
+                //the new file starts like any legacy log, the state is only in JSON lines
+                let header: Vec<&str> = current.lines().take(7).collect();
+                assert!(header[0].starts_with("FSK|") && header[0].ends_with('|'));
+                assert_eq!(header[1..], baseline_header);
+                continue;
+            }
+            let whole = std::fs::read_to_string(&rotated).unwrap() + &current;
+            let report = replay_log(&whole, quiet_config().build().unwrap()).unwrap();
+            assert_eq!(report.divergence, None);
+            //the rotated file is enough to pick up where the market was
+            let report = replay_file(dir.join(name), quiet_config().build().unwrap()).unwrap();
+            assert_eq!(report.divergence, None);
+            assert_eq!(report.replayed, 3);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     /* pub fn test_sell_success<T: Market>() {
         use unitn_market_2022::good::consts::DEFAULT_GOOD_KIND;
         let market = FskMarket::new_with_quantities(1000000., 1000000., 1000000., 1000000.);